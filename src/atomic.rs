@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::Write;
+
+
+/// Previous content of a file written with `keep_backup`.
+pub fn backup_path(path: &Path) -> PathBuf{
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".bak");
+    path.with_file_name(file_name)
+}
+
+/// Write `contents` to a temp file next to `path`, fsync it and rename it
+/// over `path`, so readers see either the old or the new content and never
+/// a truncated file. With `keep_backup` the old content is copied to
+/// `backup_path` first.
+pub fn write_atomic(path: &Path, contents: &[u8], keep_backup: bool) -> std::io::Result<()>{
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = write_synced(&temp_path, contents)
+        .and_then(|_| {
+            if keep_backup && path.exists(){
+                fs::copy(path, backup_path(path))?;
+            }
+            fs::rename(&temp_path, path)
+        });
+    if written.is_err(){
+        let _ = fs::remove_file(&temp_path);
+    }
+    written?;
+
+    sync_parent(path);
+    Ok(())
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()>{
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Persist the rename itself, best effort as not every filesystem allows
+/// syncing a directory.
+#[cfg(unix)]
+fn sync_parent(path: &Path){
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()){
+        if let Ok(dir) = File::open(parent){
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path){
+}
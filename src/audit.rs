@@ -0,0 +1,79 @@
+use std::path::Path;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+
+use crate::lock::{current_host, current_user};
+
+pub const AUDIT_FILE: &str = "vat_audit.toml";
+
+
+/// A change to the repository worth explaining later, like a forced removal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry{
+    pub at: DateTime<Utc>,
+    pub user: String,
+    pub host: String,
+    pub action: String,
+    // package or package/version
+    pub target: String,
+    pub detail: String,
+}
+
+impl AuditEntry{
+    pub fn new(action: &str, target: &str, detail: &str) -> Self{
+        Self{
+            at: Utc::now(),
+            user: current_user(),
+            host: current_host(),
+            action: action.to_string(),
+            target: target.to_string(),
+            detail: detail.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for AuditEntry{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}@{} {} {}: {}",
+            self.at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+            self.user,
+            self.host,
+            self.action,
+            self.target,
+            self.detail)
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct AuditLog{
+    #[serde(default)]
+    entries: Vec<AuditEntry>,
+}
+
+/// Entries are appended as `[[entries]]` tables, so the log never has to be
+/// rewritten.
+pub fn append_audit(path: &Path, entry: &AuditEntry) -> std::io::Result<()>{
+    let toml_string = toml::to_string(&AuditLog{ entries: vec![entry.clone()] }).map_err(std::io::Error::other)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    FileExt::lock_exclusive(&file)?;
+    file.write_all(format!("\n{}", toml_string).as_bytes())?;
+    file.sync_all()?;
+    FileExt::unlock(&file)?;
+    Ok(())
+}
+
+pub fn read_audit(path: &Path) -> std::io::Result<Vec<AuditEntry>>{
+    if !path.exists(){
+        return Ok(Vec::new());
+    }
+    let toml_string = fs::read_to_string(path)?;
+    let log: AuditLog = toml::from_str(&toml_string).map_err(std::io::Error::other)?;
+    Ok(log.entries)
+}
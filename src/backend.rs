@@ -0,0 +1,389 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use semver::Version;
+use fs2::FileExt;
+
+use crate::atomic::{backup_path, write_atomic};
+use crate::audit::{append_audit, read_audit, AuditEntry, AUDIT_FILE};
+use crate::console::Console;
+use crate::errors::{RepositoryError, RepositoryResult};
+use crate::lock::LockFile;
+use crate::store::ObjectStore;
+use crate::repository::{PackageRegistry, RepoPackage};
+
+pub const VAT_REPOSITORY_FILE: &str = "vat_repository.toml";
+const VAT_REPOSITORY_LOCK_FILE: &str = "vat_repository.lock";
+// publishes are extracted here before being moved into place
+const STAGING_DIR: &str = ".staging";
+
+
+/// Held while a repository is locked, the lock is released when it is dropped.
+pub struct RepositoryLock{
+    _guard: Box<dyn std::any::Any + Send>,
+    held: LockCheck,
+}
+
+impl RepositoryLock{
+    pub fn new<T: Send + 'static>(guard: T) -> Self{
+        Self{ _guard: Box::new(guard), held: LockCheck(Arc::new(|| true)) }
+    }
+
+    /// A lock that can be lost while held, `check` tells whether it still is.
+    pub fn checked<T: Send + 'static, F: Fn() -> bool + Send + Sync + 'static>(guard: T, check: F) -> Self{
+        Self{ _guard: Box::new(guard), held: LockCheck(Arc::new(check)) }
+    }
+
+    pub fn check(&self) -> LockCheck{
+        self.held.clone()
+    }
+}
+
+/// Whether a `RepositoryLock` is still held, kept by the repository a
+/// transaction changes so `save` can refuse to write without it.
+#[derive(Clone)]
+pub struct LockCheck(Arc<dyn Fn() -> bool + Send + Sync>);
+
+impl LockCheck{
+    pub fn is_held(&self) -> bool{
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for LockCheck{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LockCheck")
+    }
+}
+
+impl PartialEq for LockCheck{
+    fn eq(&self, other: &Self) -> bool{
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for LockCheck{}
+
+
+/// Where a repository keeps its index and published package payloads.
+pub trait RepositoryBackend: std::fmt::Debug + Send + Sync{
+    /// Human readable location, used in messages.
+    fn location(&self) -> String;
+
+    fn index_exists(&self) -> bool;
+
+    fn read_index(&self) -> RepositoryResult<HashMap<String, PackageRegistry>>;
+
+    fn write_index(&self, packages: &HashMap<String, PackageRegistry>) -> RepositoryResult<()>;
+
+    /// Store the zip `archive` of a package version and return the local
+    /// directory it can be run from.
+    fn store_payload(&self, name: &str, version: &Version, archive: &Path) -> RepositoryResult<PathBuf>;
+
+    /// Remove one version of a package, or all of them when `version` is None.
+    fn remove_payload(&self, name: &str, version: Option<&Version>) -> RepositoryResult<()>;
+
+    /// Lock the whole repository for a read-modify-write cycle, waiting up
+    /// to `timeout` for another holder.
+    fn lock(&self, timeout: Duration) -> RepositoryResult<RepositoryLock>;
+
+    /// Record a change, see `AuditEntry`.
+    fn append_audit(&self, entry: &AuditEntry) -> RepositoryResult<()>;
+
+    fn read_audit(&self) -> RepositoryResult<Vec<AuditEntry>>;
+
+    /// Local directory of a published version, for backends that only fetch
+    /// payloads when they are used.
+    fn fetch_payload(&self, package: &RepoPackage) -> RepositoryResult<PathBuf>{
+        Ok(package.package_path.clone())
+    }
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RepositoryIndex{
+    packages: HashMap<String, PackageRegistry>,
+}
+
+
+/// The original layout: `vat_repository.toml` at the root and every version
+/// extracted to `<root>/<name>/<version>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemBackend{
+    pub root: PathBuf,
+}
+
+impl FilesystemBackend{
+    pub fn new(root: PathBuf) -> Self{
+        Self{ root }
+    }
+
+    pub fn index_path(&self) -> PathBuf{
+        self.root.join(VAT_REPOSITORY_FILE)
+    }
+}
+
+impl RepositoryBackend for FilesystemBackend{
+    fn location(&self) -> String{
+        self.root.to_string_lossy().to_string()
+    }
+
+    fn index_exists(&self) -> bool{
+        self.index_path().exists()
+    }
+
+    fn read_index(&self) -> RepositoryResult<HashMap<String, PackageRegistry>>{
+        let index_path = self.index_path();
+        if !index_path.exists() {
+            return Err(RepositoryError::RepositoryNotFound("Cannot find vat_repository.toml".to_string()));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(&index_path)?;
+
+        FileExt::lock_shared(&file)?;
+        let toml_string = fs::read_to_string(&index_path)?;
+        FileExt::unlock(&file)?;
+
+        let index: RepositoryIndex = toml::from_str(&toml_string)
+            .map_err(|e| {
+                let backup = backup_path(&index_path);
+                if backup.exists(){
+                    RepositoryError::ReadError(format!("{} is damaged, the previous index is in {}: {}", index_path.display(), backup.display(), e))
+                }else{
+                    RepositoryError::ParseError(e)
+                }
+            })?;
+        Ok(index.packages)
+    }
+
+    /// Replaces the index atomically, the previous one is kept as
+    /// `vat_repository.toml.bak`.
+    fn write_index(&self, packages: &HashMap<String, PackageRegistry>) -> RepositoryResult<()>{
+        let toml_string = toml::to_string(&RepositoryIndex{ packages: packages.clone() })?;
+        write_atomic(&self.index_path(), toml_string.as_bytes(), true)?;
+        Ok(())
+    }
+
+    /// Extract into a staging directory next to the target, check every file
+    /// made it and rename it into place, so a version directory is either
+    /// complete or missing.
+    fn store_payload(&self, name: &str, version: &Version, archive: &Path) -> RepositoryResult<PathBuf>{
+        let package_path = self.root.join(name).join(version.to_string());
+        if package_path.exists(){
+            // not in the index, left over by an interrupted publish
+            Console::warn(&format!("Replacing unpublished files in {}", package_path.display()));
+            fs::remove_dir_all(&package_path)?;
+        }
+
+        let staging_path = self.root.join(STAGING_DIR).join(format!("{}-{}-{}", name, version, std::process::id()));
+        if staging_path.exists(){
+            fs::remove_dir_all(&staging_path)?;
+        }
+        fs::create_dir_all(&staging_path)?;
+
+        let staged = extract_verified(archive, &staging_path)
+            .and_then(|_| {
+                if let Some(store) = ObjectStore::open(&self.root){
+                    store.dedupe_dir(&staging_path)?;
+                }
+                fs::create_dir_all(self.root.join(name))?;
+                fs::rename(&staging_path, &package_path)?;
+                Ok(())
+            });
+        if staged.is_err() && staging_path.exists(){
+            fs::remove_dir_all(&staging_path)?;
+        }
+        staged?;
+        Ok(package_path)
+    }
+
+    fn remove_payload(&self, name: &str, version: Option<&Version>) -> RepositoryResult<()>{
+        let mut package_path = self.root.join(name);
+        if let Some(version) = version{
+            package_path = package_path.join(version.to_string());
+        }
+        if package_path.exists(){
+            fs::remove_dir_all(package_path)?;
+        }
+        if let Some(store) = ObjectStore::open(&self.root){
+            store.prune()?;
+        }
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> RepositoryResult<()>{
+        append_audit(&self.root.join(AUDIT_FILE), entry)?;
+        Ok(())
+    }
+
+    fn read_audit(&self) -> RepositoryResult<Vec<AuditEntry>>{
+        Ok(read_audit(&self.root.join(AUDIT_FILE))?)
+    }
+
+    fn lock(&self, timeout: Duration) -> RepositoryResult<RepositoryLock>{
+        let lock_file = LockFile::acquire(&self.root.join(VAT_REPOSITORY_LOCK_FILE), timeout)?;
+        let check = lock_file.held_check();
+        Ok(RepositoryLock::checked(lock_file, check))
+    }
+}
+
+
+/// Extract `archive` to `target` and check every entry was written in full.
+fn extract_verified(archive: &Path, target: &Path) -> RepositoryResult<()>{
+    let to_error = |e: zip::result::ZipError| RepositoryError::PublishError(e.to_string());
+    let mut zip_archive = zip::read::ZipArchive::new(File::open(archive)?).map_err(to_error)?;
+    zip_archive.extract(target).map_err(to_error)?;
+
+    for index in 0..zip_archive.len(){
+        let entry = zip_archive.by_index(index).map_err(to_error)?;
+        let entry_path = entry.enclosed_name()
+            .ok_or_else(|| RepositoryError::PublishError(format!("Invalid path in the archive: {}", entry.name())))?;
+        let extracted = target.join(entry_path);
+        let valid = if entry.is_dir(){
+            extracted.is_dir()
+        }else{
+            fs::symlink_metadata(&extracted).map(|metadata| metadata.is_symlink() || metadata.len() == entry.size()).unwrap_or(false)
+        };
+        if !valid{
+            return Err(RepositoryError::PublishError(format!("{} was not extracted correctly", entry.name())));
+        }
+    }
+    Ok(())
+}
+
+
+/// A backend shared between clones of a `Repository`.
+#[derive(Debug, Clone)]
+pub struct Backend(pub Arc<dyn RepositoryBackend>);
+
+impl Backend{
+    pub fn new<B: RepositoryBackend + 'static>(backend: B) -> Self{
+        Self(Arc::new(backend))
+    }
+}
+
+impl PartialEq for Backend{
+    fn eq(&self, other: &Self) -> bool{
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Backend{}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::Write;
+
+    fn archive(path: &Path, name: &str, version: &str){
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("vat.toml", options).unwrap();
+        writer.write_all(format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, version).as_bytes()).unwrap();
+        writer.add_directory("bin", options).unwrap();
+        writer.start_file("bin/tool", options).unwrap();
+        writer.write_all(b"tool").unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn temp_root(name: &str) -> PathBuf{
+        let root = std::env::temp_dir().join(format!("vat-backend-test-{}-{}", name, std::process::id()));
+        if root.exists(){
+            fs::remove_dir_all(&root).unwrap();
+        }
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn filesystem_round_trip(){
+        let root = temp_root("round-trip");
+        let backend = FilesystemBackend::new(root.clone());
+        let version = Version::parse("1.0.0").unwrap();
+        let archive_path = root.join("pkg.zip");
+        archive(&archive_path, "pkg", "1.0.0");
+
+        assert!(!backend.index_exists());
+        assert!(matches!(backend.read_index(), Err(RepositoryError::RepositoryNotFound(_))));
+
+        let package_path = backend.store_payload("pkg", &version, &archive_path).unwrap();
+        assert_eq!(package_path, root.join("pkg").join("1.0.0"));
+        assert_eq!(fs::read_to_string(package_path.join("bin").join("tool")).unwrap(), "tool");
+        assert_eq!(fs::read_dir(root.join(STAGING_DIR)).unwrap().count(), 0);
+
+        let mut package_registry = PackageRegistry::new();
+        package_registry.add_package(RepoPackage{
+            name: "pkg".to_string(),
+            version: version.clone(),
+            package_path: package_path.clone(),
+            repository: None,
+            message: Some("first".to_string()),
+            published_at: None,
+            yanked: None,
+            deprecated: None,
+            manifest: Default::default(),
+            source: String::new(),
+        });
+        let mut packages = HashMap::new();
+        packages.insert("pkg".to_string(), package_registry);
+        backend.write_index(&packages).unwrap();
+        assert!(backend.index_exists());
+        assert_eq!(backend.read_index().unwrap(), packages);
+
+        // a second write keeps the previous index as a backup
+        backend.write_index(&HashMap::new()).unwrap();
+        assert!(backend.read_index().unwrap().is_empty());
+        fs::write(backend.index_path(), "not toml [").unwrap();
+        assert!(matches!(backend.read_index(), Err(RepositoryError::ReadError(_))));
+
+        backend.remove_payload("pkg", Some(&version)).unwrap();
+        assert!(!package_path.exists());
+        assert!(root.join("pkg").exists());
+        backend.remove_payload("pkg", None).unwrap();
+        assert!(!root.join("pkg").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn interrupted_publishes_are_cleaned_up(){
+        let root = temp_root("interrupted");
+        let backend = FilesystemBackend::new(root.clone());
+        let version = Version::parse("2.0.0").unwrap();
+
+        // files left by a publish that died before the index was written,
+        // and its staging directory
+        let package_path = root.join("pkg").join("2.0.0");
+        fs::create_dir_all(&package_path).unwrap();
+        fs::write(package_path.join("stale"), "stale").unwrap();
+        let staging_path = root.join(STAGING_DIR).join(format!("pkg-2.0.0-{}", std::process::id()));
+        fs::create_dir_all(&staging_path).unwrap();
+        fs::write(staging_path.join("half"), "half").unwrap();
+
+        let archive_path = root.join("pkg.zip");
+        archive(&archive_path, "pkg", "2.0.0");
+        backend.store_payload("pkg", &version, &archive_path).unwrap();
+        assert!(!package_path.join("stale").exists());
+        assert!(package_path.join("vat.toml").exists());
+        assert!(!staging_path.exists());
+
+        // a broken archive leaves neither a version nor a staging directory
+        let broken = Version::parse("3.0.0").unwrap();
+        fs::write(&archive_path, "not a zip").unwrap();
+        assert!(matches!(backend.store_payload("pkg", &broken, &archive_path), Err(RepositoryError::PublishError(_))));
+        assert!(!root.join("pkg").join("3.0.0").exists());
+        assert_eq!(fs::read_dir(root.join(STAGING_DIR)).unwrap().count(), 0);
+
+        let lock = backend.lock(Duration::from_secs(1)).unwrap();
+        assert!(lock.check().is_held());
+        assert!(matches!(backend.lock(Duration::from_millis(0)), Err(RepositoryError::Locked(_))));
+        drop(lock);
+        assert!(backend.lock(Duration::from_millis(0)).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,7 +1,21 @@
 use clap::{Parser, Subcommand};
 use vat::Vat;
-use vat::repository::{Repository, PackageName};
+use vat::repository::{Repository, PackageName, PackageVersion};
+use vat::verify::VerifyReport;
+use vat::store::{DedupeStats, DiskUsage, ObjectStore};
 use vat::console::Console;
+use vat::process::ProcessRegistry;
+use vat::command::Limits;
+use vat::plan::Launcher;
+use vat::suite::Suite;
+use vat::stack::{Stack, Stacks};
+use vat::stack_export::StackExport;
+use vat::desktop::DesktopEntries;
+use vat::config::{RepositoryConfig, VatConfig};
+use vat::remote::{check_scheme, StaticIndex};
+use vat::retention::{parse_since, GcPlan, Retention};
+use std::path::PathBuf;
+use vat::errors::{StackError, StackResult};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const MESSAGE: &str = "Vat is a lightweight package manager / environment manager";
@@ -37,12 +51,17 @@ enum Commands {
     Publish{
         #[arg(short = 'm', long, help = "The message to publish the package with")]
         message: String,
+        #[arg(long, help = "The repository to publish to, the default one when omitted")]
+        repo: Option<String>,
         // #[arg(short, long)]
         // remote: bool,
     },
     // link
     #[command(name = "link", about = "Link current package to the repository")]
-    Link,
+    Link{
+        #[arg(long, help = "The repository to link to, the default one when omitted")]
+        repo: Option<String>,
+    },
     #[command(name = "run", about = "Run a Vat package")]
     Run{
         name: String,
@@ -52,17 +71,341 @@ enum Commands {
         append: Option<Vec<String>>,
         #[arg(short, long, default_value = "false")]
         detach: bool,
+        #[arg(long, conflicts_with = "detach", help = "Run in a new terminal window")]
+        terminal: bool,
+        #[arg(long, help = "Terminate the command after this many seconds")]
+        timeout: Option<u64>,
+        #[arg(long, help = "Limit the command's memory, in megabytes (linux only)")]
+        max_memory: Option<u64>,
+        #[arg(long, help = "Limit the command's cpu time, in seconds (linux only)")]
+        cpu_time: Option<u64>,
+        #[arg(long, help = "Limit the number of files the command can open (linux only)")]
+        open_files: Option<u64>,
+        #[arg(long, help = "Print what would be executed without running it")]
+        dry_run: bool,
+        #[arg(long, help = "Check the published packages against their checksums before running")]
+        verify: bool,
+        #[arg(last = true, help = "Arguments passed on to the command")]
+        args: Vec<String>,
     },
     #[command(name = "list", about = "List all packages in the repository")]
     List,
-    #[command(name = "remove", about = "Remove a package from the repository")]
+    #[command(name = "remove", about = "Remove a package, or a single version with package/version, from the repository")]
     Remove{
         name: String,
+        #[arg(long, help = "The repository to remove from, the default one when omitted")]
+        repo: Option<String>,
+        #[arg(long, help = "Remove even when stacks, suites or running processes use it, recorded in the audit log")]
+        force: bool,
+    },
+    #[command(name = "yank", about = "Hide a version from latest, explicit pins keep working with a warning")]
+    Yank{
+        #[arg(help = "package/version to yank")]
+        package: String,
+        #[arg(long, required_unless_present = "undo", help = "Why the version should not be used")]
+        reason: Option<String>,
+        #[arg(long, help = "Make the version available again")]
+        undo: bool,
+        #[arg(long, help = "The repository to change, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "deprecate", about = "Warn when a package or package/version is used")]
+    Deprecate{
+        package: String,
+        #[arg(long, required_unless_present = "undo", help = "What to use instead")]
+        reason: Option<String>,
+        #[arg(long, help = "Lift the deprecation")]
+        undo: bool,
+        #[arg(long, help = "The repository to change, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "channel", about = "Manage named pointers at published versions, requested as package/@channel")]
+    Channel{
+        #[command(subcommand)]
+        command: ChannelCommands,
+    },
+    #[command(name = "repo", about = "Manage the repositories searched for packages")]
+    Repo{
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+    #[command(name = "ps", about = "List processes started with --detach")]
+    Ps{
+        #[arg(short, long, help = "Include processes that have exited")]
+        all: bool,
+    },
+    #[command(name = "logs", about = "Show the output of a detached process")]
+    Logs{
+        id: u64,
+        #[arg(short, long, help = "Keep streaming output until the process exits")]
+        follow: bool,
+    },
+    #[command(name = "kill", about = "Stop a detached process")]
+    Kill{
+        id: u64,
+        #[arg(short, long, help = "Kill the process instead of asking it to terminate")]
+        force: bool,
+    },
+    #[command(name = "wrap", about = "Create a suite of wrapper executables for the commands of the given packages")]
+    Wrap{
+        name: String,
+        #[arg(required = true, num_args = 1.., help = "Packages to expose, earlier ones win on tool name clashes")]
+        packages: Vec<String>,
+    },
+    #[command(name = "suite", about = "Manage suites created with `vat wrap`")]
+    Suite{
+        #[command(subcommand)]
+        command: SuiteCommands,
+    },
+    #[command(name = "stack", about = "Manage and run stacks")]
+    Stack{
+        #[arg(long, global = true, help = "Use your own stacks instead of the repository ones")]
+        user: bool,
+        #[command(subcommand)]
+        command: StackCommands,
     },
     Test,
 }
 
 
+#[derive(Subcommand)]
+enum StackCommands {
+    #[command(name = "new", about = "Create a stack")]
+    New{
+        name: String,
+        #[arg(long = "package", short = 'p', help = "The package to run the command from, defaults to the extended stack's")]
+        package: Option<String>,
+        #[arg(long = "command", short = 'c', help = "The command to run, inherited when omitted on an extending stack")]
+        command: Option<String>,
+        #[arg(long = "append", short = 'a', num_args = 1.., help = "Append packages to the environment")]
+        append: Option<Vec<String>>,
+        #[arg(short, long, help = "Run detached, inherited from the extended stack when omitted")]
+        detach: bool,
+        #[arg(long, help = "Icon shown by launchers")]
+        icon: Option<String>,
+        #[arg(long, help = "Stack to build on")]
+        extends: Option<String>,
+        #[arg(long, num_args = 1.., help = "Package names to drop from the extended stack's append list")]
+        remove: Option<Vec<String>>,
+    },
+    #[command(name = "list", about = "List stacks")]
+    List,
+    #[command(name = "show", about = "Show a stack")]
+    Show{
+        name: String,
+        #[arg(long, help = "Show the stack with everything it inherits applied")]
+        flat: bool,
+    },
+    #[command(name = "edit", about = "Change a stack")]
+    Edit{
+        name: String,
+        #[arg(long = "package", short = 'p', help = "The package to run, pass an empty value to inherit it from the extended stack")]
+        package: Option<String>,
+        #[arg(long = "command", short = 'c')]
+        command: Option<String>,
+        #[arg(long = "append", short = 'a', num_args = 0.., help = "Replace the appended packages, pass no value to clear them")]
+        append: Option<Vec<String>>,
+        #[arg(long, short)]
+        detach: Option<bool>,
+        #[arg(long)]
+        icon: Option<String>,
+        #[arg(long, help = "Rename the stack")]
+        rename: Option<String>,
+        #[arg(long, help = "Stack to build on, pass an empty value to stop inheriting")]
+        extends: Option<String>,
+        #[arg(long, num_args = 0.., help = "Replace the package names dropped from the extended stack")]
+        remove: Option<Vec<String>>,
+    },
+    #[command(name = "rm", about = "Remove a stack")]
+    Rm{
+        name: String,
+    },
+    #[command(name = "run", about = "Run a stack")]
+    Run{
+        name: String,
+        #[arg(long, help = "Run in a new terminal window, even when the stack is detached")]
+        terminal: bool,
+        #[arg(long, help = "Print what would be executed without running it")]
+        dry_run: bool,
+        #[arg(last = true, help = "Arguments passed on to the command")]
+        args: Vec<String>,
+    },
+    #[command(name = "freeze", about = "Pin every package of a stack to the version it resolves to now")]
+    Freeze{
+        name: String,
+    },
+    #[command(name = "thaw", about = "Restore the floating package requests of a frozen stack")]
+    Thaw{
+        name: String,
+    },
+    #[command(name = "toggle", about = "Switch an appended package of a stack on or off, keeping it in the stack")]
+    Toggle{
+        name: String,
+        package: String,
+        #[arg(long, conflicts_with = "off", help = "Switch the package on")]
+        on: bool,
+        #[arg(long, help = "Switch the package off")]
+        off: bool,
+    },
+    #[command(name = "check", about = "Check that every stack resolves against the repository")]
+    Check,
+    #[command(name = "export", about = "Write stacks and the versions they resolve to into a shareable file")]
+    Export{
+        #[arg(required = true, num_args = 1..)]
+        names: Vec<String>,
+        #[arg(short, long, help = "File to write, e.g. show.vatstack")]
+        output: PathBuf,
+    },
+    #[command(name = "import", about = "Add the stacks of an exported file")]
+    Import{
+        file: PathBuf,
+        #[arg(long, help = "Pin the stacks to the exported versions")]
+        frozen: bool,
+        #[arg(long, help = "Replace existing stacks with the same name")]
+        force: bool,
+    },
+    #[command(name = "install-desktop", about = "Add a desktop launcher entry for a stack (linux)")]
+    InstallDesktop{
+        name: String,
+    },
+    #[command(name = "uninstall-desktop", about = "Remove the desktop launcher entry of a stack (linux)")]
+    UninstallDesktop{
+        name: String,
+    },
+    #[command(name = "sync-desktop", about = "Update installed desktop entries and remove the ones of deleted stacks (linux)")]
+    SyncDesktop,
+    #[command(name = "env", about = "Print the environment of a stack")]
+    Env{
+        name: String,
+    },
+}
+
+
+#[derive(Subcommand)]
+enum RepoCommands {
+    #[command(name = "list", about = "List repositories in the order they are searched")]
+    List,
+    #[command(name = "add", about = "Add a repository to the search path")]
+    Add{
+        name: String,
+        #[arg(help = "Directory of the repository, or the http url of a static index")]
+        path: String,
+        #[arg(long, help = "Position in the search path, 0 is searched first. Appended when omitted")]
+        priority: Option<usize>,
+    },
+    #[command(name = "remove", about = "Remove a repository from the search path, its files are kept")]
+    Remove{
+        name: String,
+    },
+    #[command(name = "default", about = "Set the repository publish, link and remove write to")]
+    Default{
+        name: String,
+    },
+    #[command(name = "verify", about = "Check published files against the checksums recorded when they were published")]
+    Verify{
+        #[arg(help = "Package or package/version to check, everything when omitted")]
+        package: Option<String>,
+        #[arg(long, help = "The repository to check, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "dedupe", about = "Store published files once and hardlink them into the versions")]
+    Dedupe{
+        #[arg(long, help = "The repository to deduplicate, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "du", about = "Show the logical and physical size of the published versions")]
+    Du{
+        #[arg(long, help = "The repository to measure, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "retention", about = "Show or set how many old versions `vat repo gc` keeps")]
+    Retention{
+        #[arg(help = "Package to set the policy of, the configured default for every package when omitted")]
+        package: Option<String>,
+        #[arg(long, help = "Keep the highest N versions")]
+        keep_last: Option<usize>,
+        #[arg(long, help = "Keep versions published within the last N days")]
+        keep_days: Option<u64>,
+        #[arg(long, value_parser = parse_since, help = "Keep versions published on or after this date, YYYY-MM-DD or RFC 3339")]
+        keep_since: Option<chrono::DateTime<chrono::Utc>>,
+        #[arg(long, conflicts_with_all = ["keep_last", "keep_days", "keep_since"], help = "Remove the policy")]
+        clear: bool,
+        #[arg(long, help = "The repository of the package, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "gc", about = "Remove the old versions the retention policies let go")]
+    Gc{
+        #[arg(long, help = "Show what would be removed and the space freed, without removing anything")]
+        dry_run: bool,
+        #[arg(long, help = "The repository to clean up, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "audit", about = "Show the recorded channel moves, forced removals and collected versions")]
+    Audit{
+        #[arg(help = "Only the entries of this package")]
+        package: Option<String>,
+        #[arg(long, help = "The repository to show, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "build-index", about = "Write the static index and archives to serve a repository over http")]
+    BuildIndex{
+        output: PathBuf,
+        #[arg(long, help = "The repository to export, the default one when omitted")]
+        repo: Option<String>,
+    },
+}
+
+
+#[derive(Subcommand)]
+enum ChannelCommands {
+    #[command(name = "list", about = "List the channels of a package")]
+    List{
+        package: String,
+    },
+    #[command(name = "set", about = "Point a channel at a published version, creating it if needed")]
+    Set{
+        package: String,
+        channel: String,
+        version: semver::Version,
+        #[arg(long, help = "The repository to change, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "remove", about = "Remove a channel")]
+    Remove{
+        package: String,
+        channel: String,
+        #[arg(long, help = "The repository to change, the default one when omitted")]
+        repo: Option<String>,
+    },
+    #[command(name = "latest", about = "Choose whether package/latest resolves to pre-release versions")]
+    Latest{
+        package: String,
+        #[arg(long, required_unless_present = "include_prereleases", help = "Resolve latest to the highest release")]
+        exclude_prereleases: bool,
+        #[arg(long, conflicts_with = "exclude_prereleases", help = "Resolve latest to the highest version, pre-releases included")]
+        include_prereleases: bool,
+        #[arg(long, help = "The repository to change, the default one when omitted")]
+        repo: Option<String>,
+    },
+}
+
+
+#[derive(Subcommand)]
+enum SuiteCommands {
+    #[command(name = "list", about = "List suites and their tools")]
+    List,
+    #[command(name = "refresh", about = "Re-resolve suites against the repository and rewrite their wrappers")]
+    Refresh{
+        #[arg(help = "Suite to refresh, all suites when omitted")]
+        name: Option<String>,
+    },
+    #[command(name = "remove", about = "Remove a suite and its wrappers")]
+    Remove{
+        name: String,
+    },
+}
+
+
 fn main() -> Result<(), anyhow::Error> {
     let cli = Cli::parse();
 
@@ -141,17 +484,17 @@ fn main() -> Result<(), anyhow::Error> {
             let repository = Repository::load()?;
             dbg!(&repository);
         }
-        Some(Commands::Publish { message }) => {
+        Some(Commands::Publish { message, repo }) => {
             let current_dir = std::env::current_dir()?;
             let package_read = Vat::read(current_dir);
             match package_read{
                 Ok(package) => {
-                    let mut repository = Repository::load()?;
+                    let mut repository = Repository::load_named(repo.as_deref())?;
 
                     let publish_result = repository.publish(package, &message);
                     match publish_result{
                         Ok(_) => {
-                            Console::success(&format!("Package published successfully to the repository"));
+                            Console::success(&format!("Package published successfully to the repository `{}`", repository.name));
                         }
                         Err(e) => {
                             Console::error(&e.to_string());
@@ -163,12 +506,12 @@ fn main() -> Result<(), anyhow::Error> {
                 }
             }
         }
-        Some(Commands::Link)=>{
+        Some(Commands::Link { repo })=>{
             let current_dir = std::env::current_dir()?;
             let package_read = Vat::read(current_dir);
             match package_read{
                 Ok(package)  => {
-                    let mut repository = Repository::load()?;
+                    let mut repository = Repository::load_named(repo.as_deref())?;
                     let link_result = repository.link_package(package);
                     match link_result{
                         Ok(_) => {
@@ -186,17 +529,48 @@ fn main() -> Result<(), anyhow::Error> {
         }
         Some(Commands::List)=>{
             let repository = Repository::load()?;
-            println!("Repository: {}", repository.repository_path.display());
+            if repository.is_merged(){
+                println!("Repositories: {}", repository.merged.join(", "));
+            }else{
+                println!("Repository: {}", repository.repository_path.display());
+            }
             let packages = repository.list_packages()?;
             for (package_name, package_registry) in packages{
-                println!("{}", package_name);
-                for (version, _) in package_registry.versions{
-                    println!("  {}", version.to_string());
+                match &package_registry.deprecated{
+                    Some(notice) => println!("{} [deprecated: {}]", package_name, notice.reason),
+                    None => println!("{}", package_name),
+                }
+                for (version, repo_package) in &package_registry.versions{
+                    let mut line = format!("  {}", version);
+                    if repository.is_merged(){
+                        line.push_str(&format!(" ({})", repo_package.source));
+                    }
+                    for (channel, _) in package_registry.channels.iter().filter(|(_, target)| *target == version){
+                        line.push_str(&format!(" [@{}]", channel));
+                    }
+                    if let Some(notice) = &repo_package.yanked{
+                        line.push_str(&format!(" [yanked: {}]", notice.reason));
+                    }
+                    if let Some(notice) = &repo_package.deprecated{
+                        line.push_str(&format!(" [deprecated: {}]", notice.reason));
+                    }
+                    println!("{}", line);
                 }
             }
         }
-        Some(Commands::Remove{name})=>{
-            let mut repository = Repository::load()?;
+        Some(Commands::Remove{name, repo, force})=>{
+            let mut repository = Repository::load_named(repo.as_deref())?;
+            let version = if name.contains('/'){
+                match exact_version(&name){
+                    Some(version) => Some(version),
+                    None => {
+                        Console::error(&format!("{} is not package/version", name));
+                        std::process::exit(1);
+                    }
+                }
+            }else{
+                None
+            };
             // ask for confirmation 
             Console::info(&format!("Are you sure you want to remove {} from the repository? (y/n)", name));
             let mut input = String::new();
@@ -205,18 +579,69 @@ fn main() -> Result<(), anyhow::Error> {
                 Console::info("Operation cancelled");
                 return Ok(());
             }
-            let remove_result = repository.remove_package(&name);
+            let remove_result = match &version{
+                Some((package_name, version)) => repository.remove_version(package_name, version, force),
+                None => repository.remove_package(&name, force),
+            };
             match remove_result{
                 Ok(_)=>{
                     Console::success(&format!("{} removed successfully from the repository", name));
                 }
                 Err(e)=>{
                     Console::error(&e.to_string());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::Yank { package, reason, undo, repo }) => {
+            let (package_name, version) = match exact_version(&package){
+                Some(version) => version,
+                None => {
+                    Console::error(&format!("{} is not package/version", package));
+                    std::process::exit(1);
+                }
+            };
+            let mut repository = Repository::load_named(repo.as_deref())?;
+            let reason = if undo{ None }else{ reason };
+            match repository.yank(&package_name, &version, reason.as_deref()){
+                Ok(_) => {
+                    let action = if undo{ "restored" }else{ "yanked" };
+                    Console::success(&format!("{} {}", package, action));
+                }
+                Err(e) => {
+                    Console::error(&e.to_string());
+                    std::process::exit(1);
                 }
             }
         }
-        Some(Commands::Run { name, package, append, detach }) => {
+        Some(Commands::Deprecate { package, reason, undo, repo }) => {
+            let (package_name, version) = match exact_version(&package){
+                Some((package_name, version)) => (package_name, Some(version)),
+                None if !package.contains('/') => (package.clone(), None),
+                None => {
+                    Console::error(&format!("{} is not package or package/version", package));
+                    std::process::exit(1);
+                }
+            };
+            let mut repository = Repository::load_named(repo.as_deref())?;
+            let reason = if undo{ None }else{ reason };
+            match repository.deprecate(&package_name, version.as_ref(), reason.as_deref()){
+                Ok(_) => {
+                    let action = if undo{ "is no longer deprecated" }else{ "deprecated" };
+                    Console::success(&format!("{} {}", package, action));
+                }
+                Err(e) => {
+                    Console::error(&e.to_string());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Commands::Run { name, package, append, detach, terminal, timeout, max_memory, cpu_time, open_files, dry_run, verify, args }) => {
             let current_dir = std::env::current_dir()?;
+            let limits = Limits{ timeout, max_memory, cpu_time, open_files, ..Default::default() };
+            let limits = if limits.is_empty(){ None }else{ Some(limits) };
+            let args = if args.is_empty(){ None }else{ Some(args) };
+            let launcher = Launcher::new(detach, terminal);
             let append = if append.is_some(){
                 Some(PackageName::from_vec_str(&append.unwrap()))
             }else{
@@ -227,13 +652,23 @@ fn main() -> Result<(), anyhow::Error> {
                 let output = Vat::read(current_dir);
                 match output{
                     Ok(mut vat) => {
-                        if append.is_some(){
+                        if let Some(append) = &append{
                             let repository = Repository::load()?;
-                            let resolved_env = repository.resolve_append_env(append.unwrap())?;
+                            let resolved_env = repository.resolve_append_env(append.clone())?;
                             vat.set_resolved_env(resolved_env);
                         }
                         vat.resolve_env()?;
-                        vat.run(&name, detach, None, None)?;
+                        vat.set_limits(limits);
+                        if dry_run{
+                            let mut plan = vat.plan(&name, launcher, None, args)?;
+                            if let Some(append) = append{
+                                let repository = Repository::load()?;
+                                plan.packages.extend(repository.resolve_packages(&append)?);
+                            }
+                            plan.print();
+                        }else{
+                            vat.run(&name, launcher, None, args)?;
+                        }
                     }
                     Err(e) => {
                         Console::error(&e.to_string());
@@ -243,20 +678,706 @@ fn main() -> Result<(), anyhow::Error> {
                 let repository = Repository::load()?;
                 let package_name = package.unwrap();
                 let package_name = PackageName::from_str(&package_name);
-                let run_result = repository.run(&package_name, &name, append, detach, None, None);
+                if verify{
+                    let mut package_names = vec![package_name.clone()];
+                    package_names.extend(append.clone().unwrap_or_default());
+                    let reports = repository.verify_packages(&package_names)?;
+                    if !print_verify_reports(&reports){
+                        Console::error("Refusing to run modified packages");
+                        std::process::exit(1);
+                    }
+                }
+                if dry_run{
+                    match repository.plan(&package_name, &name, append, launcher, None, args, limits){
+                        Ok(plan) => {
+                            plan.print();
+                        }
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
+                    }
+                    return Ok(());
+                }
+                let run_result = repository.run(&package_name, &name, append, launcher, None, args, limits);
                 match run_result{
                     Ok(_) => {
                     }
                     Err(e) => {
                         Console::error(&e.to_string());
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Some(Commands::Repo { command }) => {
+            let mut config = VatConfig::init()?;
+            match command{
+                RepoCommands::List => {
+                    let target = config.get_target_repository(None).map(|repository| repository.name);
+                    for (priority, repository_config) in config.get_repositories().iter().enumerate(){
+                        let default = if target.as_deref() == Some(repository_config.name.as_str()){ " (default)" }else{ "" };
+                        let location = match &repository_config.url{
+                            Some(url) => url.to_string(),
+                            None => repository_config.path.display().to_string(),
+                        };
+                        println!("{} {:<12} {}{}", priority, repository_config.name, location, default);
+                    }
+                }
+                RepoCommands::Add { name, path, priority } => {
+                    let remote = url::Url::parse(&path).ok().filter(|url| url.scheme() == "http" || url.scheme() == "https");
+                    match remote{
+                        Some(url) => {
+                            if let Err(e) = check_scheme(&url){
+                                Console::error(&e.to_string());
+                                return Ok(());
+                            }
+                            let repository_config = RepositoryConfig::remote(&name, url)
+                                .ok_or_else(|| anyhow::anyhow!("Failed to get app directory"))?;
+                            if let Err(e) = Repository::open(&repository_config){
+                                Console::error(&e.to_string());
+                                return Ok(());
+                            }
+                            config.add_repository(repository_config, priority)?;
+                        }
+                        None => {
+                            let path = std::path::absolute(path)?;
+                            config.add_repository(RepositoryConfig::new(&name, path.clone()), priority)?;
+                            // start an empty repository when the path has none yet
+                            let mut repository = Repository::new();
+                            repository.repository_path = path.clone();
+                            if !repository.backend().0.index_exists(){
+                                std::fs::create_dir_all(&path)?;
+                                repository.save()?;
+                            }
+                        }
+                    }
+                    config.save()?;
+                    Console::success(&format!("Repository `{}` added", name));
+                }
+                RepoCommands::Remove { name } => {
+                    config.remove_repository(&name)?;
+                    config.save()?;
+                    Console::success(&format!("Repository `{}` removed from the search path", name));
+                }
+                RepoCommands::Default { name } => {
+                    if !config.get_repositories().iter().any(|repository| repository.name == name){
+                        Console::error(&format!("Repository {} not found", name));
+                        return Ok(());
+                    }
+                    config.default_repository = Some(name.clone());
+                    config.save()?;
+                    Console::success(&format!("`{}` is now the default repository", name));
+                }
+                RepoCommands::Verify { package, repo } => {
+                    let repository = Repository::load_named(repo.as_deref())?;
+                    let (name, version) = match &package{
+                        Some(package) if package.contains('/') => {
+                            let package_name = PackageName::from_str(package);
+                            let version = repository.get_package_by_package_name(&package_name)
+                                .and_then(|package_registry| package_registry.resolve_version(&package_name));
+                            match version{
+                                Some(PackageVersion::Version(version)) => (Some(package_name.name), Some(version)),
+                                _ => {
+                                    Console::error(&format!("No published version matches {}", package));
+                                    std::process::exit(1);
+                                }
+                            }
+                        }
+                        Some(package) => (Some(package.clone()), None),
+                        None => (None, None),
+                    };
+                    let reports = match repository.verify(name.as_deref(), version.as_ref()){
+                        Ok(reports) => reports,
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
+                    };
+                    if !print_verify_reports(&reports){
+                        std::process::exit(1);
+                    }
+                }
+                RepoCommands::Dedupe { repo } => {
+                    let repository = Repository::load_named(repo.as_deref())?;
+                    let _lock = repository.lock()?;
+                    let store = ObjectStore::init(&repository.repository_path)?;
+                    let mut total = DedupeStats::default();
+                    for package_registry in repository.packages.values(){
+                        for repo_package in package_registry.versions.values(){
+                            if !repo_package.package_path.exists(){
+                                continue;
+                            }
+                            let stats = store.dedupe_dir(&repo_package.package_path)?;
+                            total.files += stats.files;
+                            total.linked += stats.linked;
+                            total.saved_bytes += stats.saved_bytes;
+                        }
+                    }
+                    Console::success(&format!("{} files, {} duplicates linked, {} saved", total.files, total.linked, format_size(total.saved_bytes)));
+                }
+                RepoCommands::Du { repo } => {
+                    let repository = Repository::load_named(repo.as_deref())?;
+                    let usage = DiskUsage::measure(&repository)?;
+                    println!("{:<24} {:>8} {:>12} {:>12}", "PACKAGE", "VERSIONS", "LOGICAL", "PHYSICAL");
+                    for package in &usage.packages{
+                        println!("{:<24} {:>8} {:>12} {:>12}", package.name, package.versions, format_size(package.logical), format_size(package.physical));
+                    }
+                    println!("{:<24} {:>8} {:>12} {:>12}", "total", "", format_size(usage.logical), format_size(usage.physical));
+                }
+                RepoCommands::Retention { package, keep_last, keep_days, keep_since, clear, repo } => {
+                    let changed = clear || keep_last.is_some() || keep_days.is_some() || keep_since.is_some();
+                    let retention = Some(Retention{ keep_last, keep_days, keep_since }).filter(|_| !clear);
+                    match package{
+                        Some(package) => {
+                            let mut repository = Repository::load_named(repo.as_deref())?;
+                            if !changed{
+                                let policy = repository.get_package(&package).map(|package_registry| package_registry.retention.clone());
+                                match policy{
+                                    Some(Some(policy)) => println!("{}: {}", package, policy),
+                                    Some(None) => println!("{}: {} (default)", package, config.retention.clone().unwrap_or_default()),
+                                    None => Console::error(&format!("Package {} not found", package)),
+                                }
+                                return Ok(());
+                            }
+                            if let Err(e) = repository.set_retention(&package, retention){
+                                Console::error(&e.to_string());
+                                std::process::exit(1);
+                            }
+                            Console::success(&format!("Retention of {} updated", package));
+                        }
+                        None => {
+                            if !changed{
+                                println!("default: {}", config.retention.clone().unwrap_or_default());
+                                return Ok(());
+                            }
+                            config.retention = retention;
+                            config.save()?;
+                            Console::success("Default retention updated");
+                        }
+                    }
+                }
+                RepoCommands::Gc { dry_run, repo } => {
+                    let mut repository = Repository::load_named(repo.as_deref())?;
+                    let plan = GcPlan::build(&repository, config.retention.as_ref())?;
+                    print_gc_plan(&plan);
+                    if dry_run || plan.is_empty(){
+                        return Ok(());
+                    }
+                    Console::info(&format!("Remove {} versions from `{}`? (y/n)", plan.candidates.len(), repository.name));
+                    let mut input = String::new();
+                    std::io::stdin().read_line(&mut input)?;
+                    if input.trim().to_lowercase() != "y" {
+                        Console::info("Operation cancelled");
+                        return Ok(());
+                    }
+                    // the repository may have changed while waiting, gc plans again under the lock
+                    match repository.gc(config.retention.as_ref()){
+                        Ok(plan) => Console::success(&format!("Removed {} versions, {} freed", plan.candidates.len(), format_size(plan.freed))),
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                RepoCommands::Audit { package, repo } => {
+                    let repository = Repository::load_named(repo.as_deref())?;
+                    let entries = repository.audit_log()?;
+                    let prefix = package.map(|package| format!("{}/", package));
+                    for entry in entries{
+                        let matches = prefix.as_ref()
+                            .is_none_or(|prefix| format!("{}/", entry.target).starts_with(prefix.as_str()));
+                        if matches{
+                            println!("{}", entry);
+                        }
+                    }
+                }
+                RepoCommands::BuildIndex { output, repo } => {
+                    let repository = Repository::load_named(repo.as_deref())?;
+                    std::fs::create_dir_all(&output)?;
+                    let index = StaticIndex::build(&repository, &output)?;
+                    let count: usize = index.packages.values().map(|versions| versions.len()).sum();
+                    Console::success(&format!("Indexed {} versions of {} packages in {}", count, index.packages.len(), output.display()));
+                }
+            }
+        }
+        Some(Commands::Ps { all }) => {
+            let registry = ProcessRegistry::load()?;
+            registry.trim_logs();
+            for record in registry.list(all){
+                let status = if record.is_running(){ "running" }else{ "exited" };
+                println!("{:<5} {:<8} {:<8} {}/{} {} ({})",
+                    record.id,
+                    record.pid,
+                    status,
+                    record.package,
+                    record.version,
+                    record.command,
+                    record.started_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"));
+                if !record.context.is_empty(){
+                    Console::dim(&format!("      append: {}", record.context.join(" ")));
+                }
+            }
+        }
+        Some(Commands::Logs { id, follow }) => {
+            let registry = ProcessRegistry::load()?;
+            let mut stdout = std::io::stdout();
+            if let Err(e) = registry.logs(id, follow, &mut stdout){
+                Console::error(&e.to_string());
+            }
+        }
+        Some(Commands::Kill { id, force }) => {
+            let registry = ProcessRegistry::load()?;
+            match registry.kill(id, force){
+                Ok(_) => {
+                    Console::success(&format!("Stopped process {}", id));
+                }
+                Err(e) => {
+                    Console::error(&e.to_string());
+                }
+            }
+        }
+        Some(Commands::Wrap { name, packages }) => {
+            let repository = Repository::load()?;
+            let requests = PackageName::from_vec_str(&packages);
+            match Suite::create(&name, requests, &repository){
+                Ok(suite) => {
+                    suite.print_clashes();
+                    Console::success(&format!("Suite `{}` created with {} tools", suite.name, suite.tools.len()));
+                    Console::dim(&format!("Add {} to PATH to use them", suite.bin_path().display()));
+                }
+                Err(e) => {
+                    Console::error(&e.to_string());
+                }
+            }
+        }
+        Some(Commands::Channel { command }) => {
+            match command{
+                ChannelCommands::List { package } => {
+                    let repository = Repository::load()?;
+                    let package_registry = match repository.get_package(&package){
+                        Some(package_registry) => package_registry,
+                        None => {
+                            Console::error(&format!("Package {} not found", package));
+                            std::process::exit(1);
+                        }
+                    };
+                    for (channel, version) in &package_registry.channels{
+                        println!("@{:<16} {}", channel, version);
+                    }
+                    let latest = package_registry.latest_version().map(|version| version.to_string()).unwrap_or("none".to_string());
+                    let kind = if package_registry.stable_latest{ "releases only" }else{ "pre-releases included" };
+                    println!("{:<17} {} ({})", "latest", latest, kind);
+                }
+                ChannelCommands::Set { package, channel, version, repo } => {
+                    let mut repository = Repository::load_named(repo.as_deref())?;
+                    match repository.set_channel(&package, &channel, Some(&version)){
+                        Ok(_) => Console::success(&format!("{}/@{} now points at {}", package, channel.trim_start_matches('@'), version)),
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                ChannelCommands::Remove { package, channel, repo } => {
+                    let mut repository = Repository::load_named(repo.as_deref())?;
+                    match repository.set_channel(&package, &channel, None){
+                        Ok(_) => Console::success(&format!("{}/@{} removed", package, channel.trim_start_matches('@'))),
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                ChannelCommands::Latest { package, exclude_prereleases, include_prereleases: _, repo } => {
+                    let mut repository = Repository::load_named(repo.as_deref())?;
+                    match repository.set_stable_latest(&package, exclude_prereleases){
+                        Ok(_) => {
+                            let kind = if exclude_prereleases{ "the highest release" }else{ "the highest version" };
+                            Console::success(&format!("{}/latest resolves to {}", package, kind));
+                        }
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                            std::process::exit(1);
+                        }
                     }
                 }
             }
         }
+        Some(Commands::Suite { command }) => {
+            match command{
+                SuiteCommands::List => {
+                    for suite in Suite::list()?{
+                        let frozen: Vec<String> = suite.frozen.iter().map(|package| package.to_string()).collect();
+                        println!("{} ({})", suite.name, frozen.join(" "));
+                        for (tool_name, tool) in &suite.tools{
+                            println!("  {} -> {} {}", tool_name, tool.package, tool.command);
+                        }
+                        Console::dim(&format!("  {}", suite.bin_path().display()));
+                    }
+                }
+                SuiteCommands::Refresh { name } => {
+                    let repository = Repository::load()?;
+                    let suites = match name{
+                        Some(name) => vec![Suite::load(&name)?],
+                        None => Suite::list()?,
+                    };
+                    for mut suite in suites{
+                        match suite.refresh(&repository){
+                            Ok(_) => {
+                                suite.print_clashes();
+                                Console::success(&format!("Suite `{}` refreshed", suite.name));
+                            }
+                            Err(e) => {
+                                Console::error(&format!("{}: {}", suite.name, e));
+                            }
+                        }
+                    }
+                }
+                SuiteCommands::Remove { name } => {
+                    match Suite::remove(&name){
+                        Ok(_) => {
+                            Console::success(&format!("Suite `{}` removed", name));
+                        }
+                        Err(e) => {
+                            Console::error(&e.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        Some(Commands::Stack { user, command }) => {
+            let mut stacks = if user{ Stacks::load_user()? }else{ Stacks::load()? };
+            let result = run_stack_command(&mut stacks, command, user);
+            if let Err(e) = result{
+                Console::error(&e.to_string());
+                std::process::exit(1);
+            }
+        }
         None => {
             println!("No command provided");
         }
     }
 
     Ok(())
+}
+
+
+fn get_stack(stacks: &Stacks, name: &str) -> StackResult<Stack>{
+    stacks.get(name)
+        .cloned()
+        .ok_or_else(|| StackError::StackNotFound(name.to_string()))
+}
+
+
+fn package_label(package: &PackageName) -> String{
+    if package.active{
+        package.to_string()
+    }else{
+        format!("{} (off)", package)
+    }
+}
+
+
+fn print_stack(stack: &Stack){
+    Console::bold(&stack.name);
+    if let Some(extends) = &stack.extends{
+        Console::resolved_env("  extends", extends);
+    }
+    if let Some(package) = &stack.package{
+        Console::resolved_env("  package", &package.to_string());
+    }
+    if !stack.command.is_empty(){
+        Console::resolved_env("  command", &stack.command);
+    }
+    if let Some(append) = &stack.append{
+        let append: Vec<String> = append.iter().map(package_label).collect();
+        Console::resolved_env("  append", &append.join(" "));
+    }
+    if let Some(remove) = &stack.remove{
+        Console::resolved_env("  remove", &remove.join(" "));
+    }
+    if let Some(detach) = stack.detach{
+        Console::resolved_env("  detach", &detach.to_string());
+    }
+    if let Some(frozen_at) = &stack.frozen_at{
+        Console::resolved_env("  frozen", &frozen_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    if let Some(icon) = &stack.icon{
+        Console::resolved_env("  icon", icon);
+    }
+    if let Some(modified_at) = &stack.modified_at{
+        Console::resolved_env("  modified", &modified_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+}
+
+
+/// Keep installed desktop entries in line with the stacks, if there are any.
+fn sync_desktop_entries(stacks: &Stacks, user: bool){
+    if !cfg!(target_os = "linux"){
+        return;
+    }
+    let result = DesktopEntries::new(user).and_then(|entries| entries.sync(stacks));
+    if let Err(e) = result{
+        Console::warn(&format!("Failed to update desktop entries: {}", e));
+    }
+}
+
+
+fn run_stack_command(stacks: &mut Stacks, command: StackCommands, user: bool) -> StackResult<()>{
+    let changes_stacks = matches!(command,
+        StackCommands::Edit { .. } | StackCommands::Rm { .. } | StackCommands::Freeze { .. } |
+        StackCommands::Thaw { .. } | StackCommands::Toggle { .. } | StackCommands::Import { .. });
+
+    match command{
+        StackCommands::New { name, package, command, append, detach, icon, extends, remove } => {
+            // an extending stack follows the package of the one it extends
+            if package.is_none() && extends.is_none(){
+                return Err(StackError::Command("A stack needs a --package unless it --extends another one".to_string()));
+            }
+            let package = package.map(|package| PackageName::from_str(&package));
+            if command.is_none() && extends.is_none(){
+                return Err(StackError::Command("A stack needs a --command unless it --extends another one".to_string()));
+            }
+            let mut stack = Stack::new(&name, package, &command.unwrap_or_default());
+            stack.append = append.map(|append| PackageName::from_vec_str(&append));
+            stack.detach = detach.then_some(true);
+            stack.icon = icon;
+            stack.extends = extends;
+            stack.remove = remove;
+            stacks.create(stack)?;
+            Console::success(&format!("Stack `{}` created", name));
+        }
+        StackCommands::List => {
+            for stack in stacks.list(){
+                let append = stack.append.as_ref()
+                    .map(|append| append.iter().map(package_label).collect::<Vec<_>>().join(" "))
+                    .unwrap_or_default();
+                let frozen = if stack.is_frozen(){ " (frozen)" }else{ "" };
+                let package = stack.package.as_ref()
+                    .map(|package| package.to_string())
+                    .unwrap_or_else(|| "(inherited)".to_string());
+                println!("{:<20} {} {} {}{}", stack.name, package, stack.command, append, frozen);
+            }
+        }
+        StackCommands::Show { name, flat } => {
+            if flat{
+                print_stack(&stacks.flatten(&name)?);
+            }else{
+                print_stack(&get_stack(stacks, &name)?);
+            }
+        }
+        StackCommands::Edit { name, package, command, append, detach, icon, rename, extends, remove } => {
+            let mut stack = get_stack(stacks, &name)?;
+            // `thaw` would bring back the floating definition without the edit
+            let changes = package.is_some() || command.is_some() || append.is_some() || detach.is_some()
+                || icon.is_some() || extends.is_some() || remove.is_some();
+            if changes && stack.is_frozen(){
+                return Err(StackError::InvalidStack(format!("{} is frozen, thaw it before editing it", name)));
+            }
+            if let Some(package) = package{
+                stack.package = if package.is_empty(){ None }else{ Some(PackageName::from_str(&package)) };
+            }
+            if let Some(command) = command{
+                stack.command = command;
+            }
+            if let Some(append) = append{
+                stack.append = if append.is_empty(){ None }else{ Some(PackageName::from_vec_str(&append)) };
+            }
+            if let Some(detach) = detach{
+                stack.detach = Some(detach);
+            }
+            if icon.is_some(){
+                stack.icon = icon;
+            }
+            if let Some(extends) = extends{
+                stack.extends = if extends.is_empty(){ None }else{ Some(extends) };
+            }
+            if let Some(remove) = remove{
+                stack.remove = if remove.is_empty(){ None }else{ Some(remove) };
+            }
+            let mut stack = stacks.update(stack)?;
+            if let Some(new_name) = rename{
+                stack = stacks.rename(&name, &new_name)?;
+                if cfg!(target_os = "linux"){
+                    let entries = DesktopEntries::new(user)?;
+                    if entries.is_installed(&name){
+                        entries.install(&stacks.flatten(&new_name)?)?;
+                    }
+                }
+            }
+            print_stack(&stack);
+        }
+        StackCommands::Rm { name } => {
+            Console::info(&format!("Are you sure you want to remove the stack {}? (y/n)", name));
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            if input.trim().to_lowercase() != "y" {
+                Console::info("Operation cancelled");
+                return Ok(());
+            }
+            stacks.delete(&name)?;
+            Console::success(&format!("Stack `{}` removed", name));
+        }
+        StackCommands::Run { name, terminal, dry_run, args } => {
+            let stack = stacks.flatten(&name)?;
+            let args = if args.is_empty(){ None }else{ Some(args) };
+            if dry_run{
+                stack.dry_run(terminal, None, args)?.print();
+            }else{
+                stack.run(terminal, None, args)?;
+            }
+        }
+        StackCommands::Freeze { name } => {
+            let repository = Repository::load()?;
+            let stack = stacks.freeze(&name, &repository)?;
+            print_stack(&stack);
+            Console::success(&format!("Stack `{}` frozen", name));
+        }
+        StackCommands::Thaw { name } => {
+            let stack = stacks.thaw(&name)?;
+            print_stack(&stack);
+            Console::success(&format!("Stack `{}` thawed", name));
+        }
+        StackCommands::Toggle { name, package, on, off } => {
+            let active = if on{ Some(true) }else if off{ Some(false) }else{ None };
+            let active = stacks.toggle(&name, &package, active)?;
+            let state = if active{ "on" }else{ "off" };
+            Console::success(&format!("`{}` switched {} in stack `{}`", package, state, name));
+        }
+        StackCommands::Check => {
+            let repository = Repository::load()?;
+            let failures = stacks.check(&repository);
+            for stack in stacks.list(){
+                match failures.iter().find(|(name, _)| name == &stack.name){
+                    Some((_, problems)) => {
+                        Console::error(&format!("{}: broken", stack.name));
+                        for problem in problems{
+                            Console::dim(&format!("  - {}", problem));
+                        }
+                    }
+                    None => {
+                        Console::success(&format!("{}: ok", stack.name));
+                    }
+                }
+            }
+            if !failures.is_empty(){
+                return Err(StackError::InvalidStack(format!("{} of {} stacks do not resolve", failures.len(), stacks.list().len())));
+            }
+        }
+        StackCommands::Export { names, output } => {
+            let repository = Repository::load()?;
+            let export = StackExport::create(stacks, &names, &repository)?;
+            export.save(&output)?;
+            Console::success(&format!("Exported {} stacks to {}", export.stacks.len(), output.display()));
+        }
+        StackCommands::Import { file, frozen, force } => {
+            let export = StackExport::read(&file)?;
+            let imported = export.import(stacks, frozen, force)?;
+            for stack in &imported{
+                Console::success(&format!("Imported stack `{}`", stack.name));
+            }
+
+            let repository = Repository::load()?;
+            for (name, problems) in export.check(&repository){
+                Console::warn(&format!("{}: exported versions missing from the repository", name));
+                for problem in problems{
+                    Console::dim(&format!("  - {}", problem));
+                }
+            }
+        }
+        StackCommands::InstallDesktop { name } => {
+            let stack = stacks.flatten(&name)?;
+            let entry_path = DesktopEntries::new(user)?.install(&stack)?;
+            Console::success(&format!("Desktop entry written to {}", entry_path.display()));
+        }
+        StackCommands::UninstallDesktop { name } => {
+            DesktopEntries::new(user)?.uninstall(&name)?;
+            Console::success(&format!("Desktop entry of `{}` removed", name));
+        }
+        StackCommands::SyncDesktop => {
+            let entries = DesktopEntries::new(user)?;
+            for name in entries.sync(stacks)?{
+                Console::info(&format!("Removed the desktop entry of deleted stack `{}`", name));
+            }
+            Console::success("Desktop entries are up to date");
+        }
+        StackCommands::Env { name } => {
+            let stack = stacks.flatten(&name)?;
+            let env = stack.env_from_stack()?;
+            let mut keys: Vec<&String> = env.keys().collect();
+            keys.sort();
+            for key in keys{
+                Console::resolved_env(key, &env[key]);
+            }
+        }
+    }
+
+    if changes_stacks{
+        sync_desktop_entries(stacks, user);
+    }
+    Ok(())
+}
+
+
+/// Print each report, returns false when a package does not match its manifest.
+fn print_verify_reports(reports: &[VerifyReport]) -> bool{
+    let mut ok = true;
+    for report in reports{
+        let label = format!("{}/{}", report.name, report.version);
+        if report.unrecorded{
+            Console::warn(&format!("{}: no checksums recorded", label));
+        }else if report.is_ok(){
+            Console::success(&format!("{}: ok", label));
+        }else{
+            ok = false;
+            Console::error(&format!("{}: modified", label));
+            for problem in report.problems(){
+                Console::dim(&format!("  {}", problem));
+            }
+        }
+    }
+    ok
+}
+
+
+fn print_gc_plan(plan: &GcPlan){
+    for candidate in &plan.candidates{
+        let published = candidate.published_at
+            .map(|at| at.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string())
+            .unwrap_or("unknown".to_string());
+        println!("{:<32} {:>10} {:>12}  {}",
+            format!("{}/{}", candidate.name, candidate.version),
+            published,
+            format_size(candidate.size),
+            candidate.policy);
+    }
+    for (name, version, dependent) in &plan.in_use{
+        Console::dim(&format!("keeping {}/{}: {}", name, version, dependent));
+    }
+    if plan.is_empty(){
+        Console::info("Nothing to remove");
+    }else{
+        Console::info(&format!("{} versions, {} freed on disk", plan.candidates.len(), format_size(plan.freed)));
+    }
+}
+
+
+fn format_size(bytes: u64) -> String{
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1{
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0{ format!("{} B", bytes) }else{ format!("{:.1} {}", size, units[unit]) }
+}
+
+
+/// Name and version of an exact `package/version` request.
+fn exact_version(request: &str) -> Option<(String, semver::Version)>{
+    let (name, version) = request.split_once('/')?;
+    let version = semver::Version::parse(version).ok()?;
+    Some((name.to_string(), version))
 }
\ No newline at end of file
@@ -2,6 +2,11 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+// seconds of cpu time past `cpu_time` before the kernel kills a command that
+// ignores SIGXCPU
+#[cfg(target_os = "linux")]
+const CPU_TIME_KILL_AFTER: u64 = 5;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Commands{
     #[serde(flatten)]
@@ -13,6 +18,23 @@ pub struct Commands{
 
 
 impl Commands{
+    /// Names of the commands available on the current os.
+    pub fn names(&self) -> Vec<String>{
+        let os_commands = match std::env::consts::OS{
+            "windows" => self.windows.as_ref(),
+            "macos" => self.macos.as_ref(),
+            "linux" => self.linux.as_ref(),
+            _ => None,
+        };
+        let mut names: Vec<String> = self.global.keys()
+            .chain(os_commands.into_iter().flat_map(|commands| commands.keys()))
+            .cloned()
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     pub fn get_command(&self, command: &str) -> Option<Command>{
         // get the global command
         let mut output = None;
@@ -54,11 +76,202 @@ impl Commands{
 pub struct Command{
     pub values: Vec<String>,
     pub cwd: Option<String>,
+    // run the values as a single script through the platform shell
+    pub shell: Option<bool>,
+    pub limits: Option<Limits>,
 }
 
 impl Command{
     pub fn new(values: Vec<String>) -> Self{
-        Self{values, cwd: None}
+        Self{values, cwd: None, shell: None, limits: None}
+    }
+
+    pub fn is_shell(&self) -> bool{
+        self.shell.unwrap_or(false)
+    }
+
+    /// Script passed to the platform shell for shell-form commands. Additional
+    /// arguments are quoted so they reach the program as single words.
+    pub fn shell_script(&self, additional_args: &[String]) -> String{
+        let mut script = self.values.join(" ");
+        for arg in additional_args{
+            script.push(' ');
+            script.push_str(&shell_quote(arg));
+        }
+        script
+    }
+
+    /// Full argv for this command. Argv-form values are passed through as-is,
+    /// shell-form values are wrapped in `sh -c` / `cmd /C`.
+    pub fn argv(&self, additional_args: &[String]) -> Vec<String>{
+        if self.is_shell(){
+            let script = self.shell_script(additional_args);
+            if cfg!(target_os = "windows"){
+                vec!["cmd".to_string(), "/C".to_string(), script]
+            }else{
+                vec!["sh".to_string(), "-c".to_string(), script]
+            }
+        }else{
+            let mut argv = self.values.clone();
+            argv.extend(additional_args.iter().cloned());
+            argv
+        }
+    }
+
+    pub fn to_process(&self, additional_args: &[String]) -> std::process::Command{
+        let argv = self.argv(additional_args);
+        let mut process = std::process::Command::new(&argv[0]);
+
+        #[cfg(target_os = "windows")]
+        {
+            // cmd.exe does its own parsing, so the script must not be re-quoted
+            use std::os::windows::process::CommandExt;
+            if self.is_shell(){
+                process.arg(&argv[1]);
+                process.raw_arg(&argv[2]);
+            }else{
+                process.args(&argv[1..]);
+            }
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        process.args(&argv[1..]);
+
+        if let Some(cwd) = &self.cwd{
+            process.current_dir(cwd);
+        }
+        process
     }
+}
 
+
+/// Quote a single argument for the platform shell.
+pub fn shell_quote(arg: &str) -> String{
+    if cfg!(target_os = "windows"){
+        quote_cmd(arg)
+    }else{
+        quote_posix(arg)
+    }
+}
+
+pub fn quote_posix(arg: &str) -> String{
+    let is_safe = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if is_safe{
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+pub fn quote_cmd(arg: &str) -> String{
+    let is_safe = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./\\=:,+@".contains(c));
+    if is_safe{
+        return arg.to_string();
+    }
+    // inside quotes only `"` and `%` are special to cmd.exe
+    let escaped = arg.replace('"', "\"\"").replace('%', "\"^%\"");
+    format!("\"{}\"", escaped)
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits{
+    // wall-clock timeout in seconds
+    pub timeout: Option<u64>,
+    // seconds between SIGTERM and SIGKILL once the timeout is hit
+    pub timeout_grace: Option<u64>,
+    // address space limit in megabytes (linux only)
+    pub max_memory: Option<u64>,
+    // cpu time limit in seconds (linux only)
+    pub cpu_time: Option<u64>,
+    // max open file descriptors (linux only)
+    pub open_files: Option<u64>,
+}
+
+impl Limits{
+    pub fn is_empty(&self) -> bool{
+        self == &Limits::default()
+    }
+
+    pub fn has_rlimits(&self) -> bool{
+        self.max_memory.is_some() || self.cpu_time.is_some() || self.open_files.is_some()
+    }
+
+    /// Values set in `other` win over the ones in `self`.
+    pub fn merge(&self, other: &Limits) -> Limits{
+        Limits{
+            timeout: other.timeout.or(self.timeout),
+            timeout_grace: other.timeout_grace.or(self.timeout_grace),
+            max_memory: other.max_memory.or(self.max_memory),
+            cpu_time: other.cpu_time.or(self.cpu_time),
+            open_files: other.open_files.or(self.open_files),
+        }
+    }
+
+    /// Apply the resource limits to the child before it execs.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, process: &mut std::process::Command){
+        use std::os::unix::process::CommandExt;
+
+        if !self.has_rlimits(){
+            return;
+        }
+        let limits = self.clone();
+        let set_limit = |resource, value: u64, hard: u64| -> std::io::Result<()>{
+            let limit = libc::rlimit{ rlim_cur: value as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+            if unsafe { libc::setrlimit(resource, &limit) } != 0{
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        unsafe {
+            process.pre_exec(move || {
+                if let Some(max_memory) = limits.max_memory{
+                    set_limit(libc::RLIMIT_AS, max_memory * 1024 * 1024, max_memory * 1024 * 1024)?;
+                }
+                if let Some(cpu_time) = limits.cpu_time{
+                    // SIGXCPU at the limit, the kernel only SIGKILLs at the
+                    // hard limit, so the cause can be told apart
+                    set_limit(libc::RLIMIT_CPU, cpu_time, cpu_time + CPU_TIME_KILL_AFTER)
+                        .or_else(|_| set_limit(libc::RLIMIT_CPU, cpu_time, cpu_time))?;
+                }
+                if let Some(open_files) = limits.open_files{
+                    set_limit(libc::RLIMIT_NOFILE, open_files, open_files)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _process: &mut std::process::Command){
+        if self.has_rlimits(){
+            crate::console::Console::warn("Resource limits are only supported on linux, ignoring them");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn posix_quoting(){
+        assert_eq!(quote_posix("plain-arg_1.txt"), "plain-arg_1.txt");
+        assert_eq!(quote_posix("--opt=a,b"), "--opt=a,b");
+        assert_eq!(quote_posix(""), "''");
+        assert_eq!(quote_posix("two words"), "'two words'");
+        assert_eq!(quote_posix("it's"), "'it'\\''s'");
+        assert_eq!(quote_posix("$HOME;rm"), "'$HOME;rm'");
+    }
+
+    #[test]
+    fn cmd_quoting(){
+        assert_eq!(quote_cmd("C:\\tools\\app.exe"), "C:\\tools\\app.exe");
+        assert_eq!(quote_cmd(""), "\"\"");
+        assert_eq!(quote_cmd("two words"), "\"two words\"");
+        assert_eq!(quote_cmd("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote_cmd("100%"), "\"100\"^%\"\"");
+        assert_eq!(quote_cmd("a&b"), "\"a&b\"");
+    }
 }
\ No newline at end of file
@@ -1,21 +1,68 @@
 use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
 use serde::{Serialize, Deserialize};
 use dirs_next::{config_dir, document_dir};
 use std::fs;
 use crate::repository::Repository;
+use crate::atomic::write_atomic;
+use crate::retention::Retention;
 
 const CONFIG_FILE_NAME: &str = "vat.config";
+pub const DEFAULT_REPOSITORY: &str = "default";
+const DEFAULT_LOCK_TIMEOUT: u64 = 60;
+pub const MISSING_VAT_EXECUTABLE: &str = "Cannot find the vat binary, set `vat_executable` in vat.config";
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VatConfig{
     pub repository_path: PathBuf,   
     pub packages_path: PathBuf,
+    // repositories searched in priority order, only `repository_path` when empty
+    #[serde(default)]
+    pub repositories: Vec<RepositoryConfig>,
+    // repository `publish`, `link` and `remove` write to by default
+    pub default_repository: Option<String>,
+    // seconds to wait for another user's repository lock
+    #[serde(default)]
+    pub lock_timeout: Option<u64>,
+    // retention of the packages that have none of their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    // the vat binary suite wrappers and desktop entries run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vat_executable: Option<PathBuf>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepositoryConfig{
+    pub name: String,
+    // for a remote repository, the local cache of its index and payloads
+    pub path: PathBuf,
+    // served over http with a static index, read only
+    #[serde(default)]
+    pub url: Option<Url>,
+}
+
+impl RepositoryConfig{
+    pub fn new(name: &str, path: PathBuf) -> Self{
+        Self{ name: name.to_string(), path, url: None }
+    }
+
+    pub fn remote(name: &str, url: Url) -> Option<Self>{
+        let path = VatConfig::get_app_dir()?.join("remotes").join(name);
+        Some(Self{ name: name.to_string(), path, url: Some(url) })
+    }
+
+    pub fn is_remote(&self) -> bool{
+        self.url.is_some()
+    }
 }
 
 
 impl VatConfig {
     pub fn new() -> Self{
-        VatConfig { repository_path: PathBuf::new(), packages_path: PathBuf::new() }
+        VatConfig { repository_path: PathBuf::new(), packages_path: PathBuf::new(), repositories: Vec::new(), default_repository: None, lock_timeout: None, retention: None, vat_executable: None }
     }
 
     pub fn init() -> Result<Self, anyhow::Error> {
@@ -82,13 +129,91 @@ impl VatConfig {
         self.repository_path = path;
     }
 
+    /// Repositories in the order lookups search them.
+    pub fn get_repositories(&self) -> Vec<RepositoryConfig> {
+        if self.repositories.is_empty(){
+            return vec![RepositoryConfig::new(DEFAULT_REPOSITORY, self.repository_path.clone())];
+        }
+        self.repositories.clone()
+    }
+
+    /// The repository to write to, `name` or the default one.
+    pub fn get_target_repository(&self, name: Option<&str>) -> Option<RepositoryConfig> {
+        let repositories = self.get_repositories();
+        match name.or(self.default_repository.as_deref()){
+            Some(name) => repositories.into_iter().find(|repository| repository.name == name),
+            None => repositories.into_iter().next(),
+        }
+    }
+
+    /// Add a repository to the search path, at `priority` (0 is searched
+    /// first) or last. The implicit default repository is kept in the list.
+    pub fn add_repository(&mut self, repository: RepositoryConfig, priority: Option<usize>) -> Result<(), anyhow::Error> {
+        let mut repositories = self.get_repositories();
+        if repositories.iter().any(|other| other.name == repository.name){
+            return Err(anyhow::anyhow!("Repository {} already exists", repository.name));
+        }
+        match priority{
+            Some(priority) => repositories.insert(priority.min(repositories.len()), repository),
+            None => repositories.push(repository),
+        }
+        self.repositories = repositories;
+        Ok(())
+    }
+
+    pub fn remove_repository(&mut self, name: &str) -> Result<(), anyhow::Error> {
+        let mut repositories = self.get_repositories();
+        let count = repositories.len();
+        repositories.retain(|repository| repository.name != name);
+        if repositories.len() == count{
+            return Err(anyhow::anyhow!("Repository {} not found", name));
+        }
+        if repositories.is_empty(){
+            return Err(anyhow::anyhow!("Cannot remove the last repository"));
+        }
+        if self.default_repository.as_deref() == Some(name){
+            self.default_repository = None;
+        }
+        self.repositories = repositories;
+        Ok(())
+    }
+
     pub fn save(&self) -> Result<(), anyhow::Error> {
-        let config_path = VatConfig::get_app_dir().unwrap().join(CONFIG_FILE_NAME);
-        let config_str = toml::to_string(&self).unwrap();
-        fs::write(config_path, config_str).unwrap();
+        let config_path = VatConfig::get_app_dir()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get app directory"))?
+            .join(CONFIG_FILE_NAME);
+        let config_str = toml::to_string(&self)?;
+        write_atomic(&config_path, config_str.as_bytes(), false)?;
         Ok(())
     }
 
+    pub fn get_lock_timeout(&self) -> Duration {
+        Duration::from_secs(self.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT))
+    }
+
+    /// The vat binary generated files should run: `vat_executable`, the
+    /// running binary when it is vat, or `vat` on the PATH. Library callers
+    /// like a launcher are not vat themselves.
+    pub fn get_vat_executable(&self) -> Option<PathBuf> {
+        if let Some(vat_executable) = &self.vat_executable{
+            return Some(vat_executable.clone());
+        }
+        let file_name = format!("vat{}", std::env::consts::EXE_SUFFIX);
+        let current_exe = std::env::current_exe().ok()
+            .filter(|current_exe| current_exe.file_name().is_some_and(|name| name == file_name.as_str()));
+        if current_exe.is_some(){
+            return current_exe;
+        }
+        let path = std::env::var_os("PATH")?;
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(&file_name))
+            .find(|candidate| candidate.is_file())
+    }
+
+    pub fn get_state_dir() -> Option<PathBuf> {
+        VatConfig::get_app_dir().map(|path| path.join("state"))
+    }
+
     pub fn get_app_dir() -> Option<PathBuf> {
         let app_name = String::from("Vat");
 
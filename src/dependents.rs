@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use semver::Version;
+
+use crate::config::VatConfig;
+use crate::console::Console;
+use crate::process::ProcessRegistry;
+use crate::repository::{PackageName, PackageVersion, Repository};
+use crate::stack::{Stack, Stacks, STACKS_FILE};
+use crate::suite::Suite;
+
+
+const CHANNEL_KIND: &str = "channel";
+
+
+/// Something that would break if a package or version was removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependent{
+    // stack, user stack, suite or process
+    pub kind: String,
+    pub name: String,
+    pub reference: PackageName,
+}
+
+impl std::fmt::Display for Dependent{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} `{}` uses {}", self.kind, self.name, self.reference)
+    }
+}
+
+
+impl Dependent{
+    /// Whether the reference breaks when the package, or one version of it,
+    /// goes. `latest` only depends on a version when nothing else would
+    /// resolve, yanked and excluded pre-release versions don't count. A
+    /// package's own channels go along with the whole package.
+    pub fn uses(&self, repository: &Repository, package_name: &str, version: Option<&Version>) -> bool{
+        if self.reference.name != package_name{
+            return false;
+        }
+        let version = match version{
+            Some(version) => version,
+            None => return self.kind != CHANNEL_KIND,
+        };
+        match &self.reference.version{
+            PackageVersion::Version(pinned) => pinned == version,
+            PackageVersion::Latest => repository.get_package(package_name)
+                .is_some_and(|package_registry| {
+                    package_registry.latest_version().is_some()
+                        && package_registry.latest_version_except(Some(version)).is_none()
+                }),
+            PackageVersion::Channel(channel) => repository.get_package(package_name)
+                .and_then(|package_registry| package_registry.channels.get(channel))
+                .is_some_and(|target| target == version),
+            PackageVersion::Main => false,
+        }
+    }
+}
+
+
+/// Stored stacks, suites and running processes that reference `package_name`,
+/// or only `version` of it when given.
+pub fn find_dependents(repository: &Repository, package_name: &str, version: Option<&Version>) -> Vec<Dependent>{
+    collect_dependents(repository).into_iter()
+        .filter(|dependent| dependent.uses(repository, package_name, version))
+        .collect()
+}
+
+/// Every package request of the stored stacks, suites and running processes.
+/// Sources that cannot be read are skipped with a warning.
+pub fn collect_dependents(repository: &Repository) -> Vec<Dependent>{
+    let mut dependents = Vec::new();
+
+    for (package_name, package_registry) in &repository.packages{
+        for (channel, version) in &package_registry.channels{
+            dependents.push(Dependent{
+                kind: CHANNEL_KIND.to_string(),
+                name: format!("@{}", channel),
+                reference: PackageName{ name: package_name.clone(), version: PackageVersion::Version(version.clone()), active: true },
+            });
+        }
+    }
+
+    let mut stacks_paths: Vec<(&str, PathBuf)> = vec![("stack", repository.repository_path.join(STACKS_FILE))];
+    if let Ok(config) = VatConfig::init(){
+        stacks_paths.push(("stack", config.repository_path.join(STACKS_FILE)));
+    }
+    if let Some(app_dir) = VatConfig::get_app_dir(){
+        stacks_paths.push(("user stack", app_dir.join(STACKS_FILE)));
+    }
+    stacks_paths.dedup_by(|a, b| a.1 == b.1);
+    for (kind, stacks_path) in stacks_paths{
+        match Stacks::open(stacks_path.clone()){
+            Ok(stacks) => {
+                for stack in stacks.list(){
+                    for reference in stack_references(stack){
+                        dependents.push(Dependent{ kind: kind.to_string(), name: stack.name.clone(), reference });
+                    }
+                }
+            }
+            Err(e) => Console::warn(&format!("Cannot check the stacks in {}: {}", stacks_path.display(), e)),
+        }
+    }
+
+    match Suite::list(){
+        Ok(suites) => {
+            for suite in suites{
+                for reference in suite.requests.iter().chain(suite.frozen.iter()){
+                    dependents.push(Dependent{ kind: "suite".to_string(), name: suite.name.clone(), reference: reference.clone() });
+                }
+            }
+        }
+        Err(e) => Console::warn(&format!("Cannot check the suites: {}", e)),
+    }
+
+    match ProcessRegistry::load(){
+        Ok(registry) => {
+            for record in registry.list(false){
+                let main = PackageName::from_str(&format!("{}/{}", record.package, record.version));
+                let context = record.context.iter().map(|name| PackageName::from_str(name));
+                for reference in std::iter::once(main).chain(context){
+                    dependents.push(Dependent{ kind: "process".to_string(), name: record.id.to_string(), reference });
+                }
+            }
+        }
+        Err(e) => Console::warn(&format!("Cannot check the running processes: {}", e)),
+    }
+
+    dependents.dedup();
+    dependents
+}
+
+/// Packages a stack requests, including the floating definition of a frozen
+/// stack since thawing brings it back.
+fn stack_references(stack: &Stack) -> Vec<PackageName>{
+    let mut package_names: Vec<PackageName> = stack.package.iter().cloned().collect();
+    package_names.extend(stack.append.clone().unwrap_or_default());
+    if let Some(floating) = &stack.floating{
+        package_names.extend(stack_references(floating));
+    }
+    package_names
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::repository::PackageRegistry;
+
+    #[test]
+    fn own_channels_only_hold_single_versions(){
+        let version = Version::parse("1.0.0").unwrap();
+        let mut package_registry = PackageRegistry::new();
+        package_registry.channels.insert("stable".to_string(), version.clone());
+        let mut repository = Repository::new();
+        repository.packages.insert("pkg".to_string(), package_registry);
+
+        let channel = Dependent{
+            kind: CHANNEL_KIND.to_string(),
+            name: "@stable".to_string(),
+            reference: PackageName{ name: "pkg".to_string(), version: PackageVersion::Version(version.clone()), active: true },
+        };
+        assert!(channel.uses(&repository, "pkg", Some(&version)));
+        assert!(!channel.uses(&repository, "pkg", Some(&Version::parse("2.0.0").unwrap())));
+        assert!(!channel.uses(&repository, "pkg", None));
+
+        let stack = Dependent{ kind: "stack".to_string(), name: "shot".to_string(), reference: PackageName::from_str("pkg/@stable") };
+        assert!(stack.uses(&repository, "pkg", None));
+        assert!(stack.uses(&repository, "pkg", Some(&version)));
+        assert!(!stack.uses(&repository, "other", None));
+    }
+}
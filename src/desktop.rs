@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use crate::config::{VatConfig, MISSING_VAT_EXECUTABLE};
+use crate::errors::{StackError, StackResult};
+use crate::stack::{Stack, Stacks};
+
+const ENTRY_PREFIX: &str = "vat-stack-";
+const USER_ENTRY_PREFIX: &str = "vat-user-stack-";
+
+
+/// Freedesktop launcher entries for stacks, written to
+/// `~/.local/share/applications`.
+pub struct DesktopEntries{
+    pub applications_path: PathBuf,
+    pub icons_path: PathBuf,
+    // entries for `--user` stacks
+    pub user: bool,
+}
+
+impl DesktopEntries{
+    pub fn new(user: bool) -> StackResult<Self>{
+        if !cfg!(target_os = "linux"){
+            return Err(StackError::DesktopError("Desktop entries are only supported on linux".to_string()));
+        }
+        let data_dir = dirs::data_dir()
+            .ok_or_else(|| StackError::DesktopError("Failed to get the data directory".to_string()))?;
+        Ok(Self{
+            applications_path: data_dir.join("applications"),
+            icons_path: data_dir.join("icons").join("vat"),
+            user,
+        })
+    }
+
+    fn prefix(&self) -> &str{
+        if self.user{ USER_ENTRY_PREFIX }else{ ENTRY_PREFIX }
+    }
+
+    pub fn entry_path(&self, stack_name: &str) -> PathBuf{
+        self.applications_path.join(format!("{}{}.desktop", self.prefix(), stack_name))
+    }
+
+    pub fn is_installed(&self, stack_name: &str) -> bool{
+        self.entry_path(stack_name).exists()
+    }
+
+    /// Names of the stacks with an installed entry.
+    pub fn installed(&self) -> StackResult<Vec<String>>{
+        let mut names = Vec::new();
+        if !self.applications_path.exists(){
+            return Ok(names);
+        }
+        for entry in fs::read_dir(&self.applications_path)?{
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_prefix(self.prefix()).and_then(|name| name.strip_suffix(".desktop")){
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn install(&self, stack: &Stack) -> StackResult<PathBuf>{
+        fs::create_dir_all(&self.applications_path)?;
+        let icon = match &stack.icon{
+            Some(icon) => Some(self.install_icon(&stack.name, icon)?),
+            None => None,
+        };
+
+        let vat_exe = VatConfig::init()
+            .map_err(|e| StackError::ConfigError(e.to_string()))?
+            .get_vat_executable()
+            .ok_or_else(|| StackError::ConfigError(MISSING_VAT_EXECUTABLE.to_string()))?;
+        let mut exec = vec![vat_exe.to_string_lossy().to_string(), "stack".to_string(), "run".to_string(), stack.name.clone()];
+        if self.user{
+            exec.push("--user".to_string());
+        }
+        let exec: Vec<String> = exec.iter().map(|arg| quote_exec(arg)).collect();
+
+        let mut entry = String::from("[Desktop Entry]\n");
+        entry.push_str("Type=Application\n");
+        entry.push_str(&format!("Name={}\n", stack.name));
+        let package = stack.package.as_ref().map(|package| package.to_string()).unwrap_or_default();
+        entry.push_str(&format!("Comment=Vat stack: {} {}\n", package, stack.command));
+        entry.push_str(&format!("Exec={}\n", exec.join(" ")));
+        if let Some(icon) = icon{
+            entry.push_str(&format!("Icon={}\n", icon));
+        }
+        entry.push_str("Terminal=false\n");
+        entry.push_str("Categories=Graphics;\n");
+        entry.push_str(&format!("X-Vat-Stack={}\n", stack.name));
+
+        let entry_path = self.entry_path(&stack.name);
+        fs::write(&entry_path, entry)?;
+        Ok(entry_path)
+    }
+
+    /// Icons that are files get copied next to the other vat icons, anything
+    /// else is used as an icon theme name.
+    fn install_icon(&self, stack_name: &str, icon: &str) -> StackResult<String>{
+        let icon_path = Path::new(icon);
+        if !icon_path.is_file(){
+            return Ok(icon.to_string());
+        }
+        fs::create_dir_all(&self.icons_path)?;
+        let extension = icon_path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+        let target = self.icons_path.join(format!("{}{}{}", self.prefix(), stack_name, extension));
+        fs::copy(icon_path, &target)?;
+        Ok(target.to_string_lossy().to_string())
+    }
+
+    pub fn uninstall(&self, stack_name: &str) -> StackResult<()>{
+        let entry_path = self.entry_path(stack_name);
+        if !entry_path.exists(){
+            return Err(StackError::DesktopError(format!("No desktop entry installed for {}", stack_name)));
+        }
+        fs::remove_file(entry_path)?;
+        if self.icons_path.exists(){
+            let icon_prefix = format!("{}{}.", self.prefix(), stack_name);
+            for entry in fs::read_dir(&self.icons_path)?{
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with(&icon_prefix){
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite installed entries from the current stacks and remove the ones
+    /// whose stack is gone. Returns the removed stack names.
+    pub fn sync(&self, stacks: &Stacks) -> StackResult<Vec<String>>{
+        let mut removed = Vec::new();
+        for name in self.installed()?{
+            match stacks.get(&name){
+                Some(_) => {
+                    self.install(&stacks.flatten(&name)?)?;
+                }
+                None => {
+                    self.uninstall(&name)?;
+                    removed.push(name);
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+
+/// Quote an argument of the Exec key as the desktop entry spec asks.
+fn quote_exec(arg: &str) -> String{
+    // field codes start with `%`, a literal one is doubled
+    let arg = arg.replace('%', "%%");
+    let reserved = " \t\n\"'\\><~|&;$*?#()`";
+    if !arg.is_empty() && !arg.chars().any(|c| reserved.contains(c)){
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars(){
+        if "\"`$\\".contains(c){
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    // the value itself is an escaped string, so backslashes are doubled again
+    quoted.replace('\\', "\\\\")
+}
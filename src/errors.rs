@@ -1,4 +1,5 @@
 use thiserror::Error;
+use crate::repository::PackageName;
 
 
 #[derive(Error, Debug)]
@@ -7,7 +8,37 @@ pub enum StackError{
     Command(String),
 
     #[error("Repository Error: {0}")]
-    RepositoryError(#[from] RepositoryError)
+    RepositoryError(#[from] RepositoryError),
+
+    #[error("Stack not found: {0}")]
+    StackNotFound(String),
+
+    #[error("Stack already exists: {0}")]
+    StackAlreadyExists(String),
+
+    #[error("Stack inherits from itself: {0}")]
+    StackCycle(String),
+
+    #[error("Stack is still used: {0}")]
+    StackInUse(String),
+
+    #[error("Invalid stack: {0}")]
+    InvalidStack(String),
+
+    #[error("Desktop entry error: {0}")]
+    DesktopError(String),
+
+    #[error("Error loading the config: {0}")]
+    ConfigError(String),
+
+    #[error("Error writing the stacks: {0}")]
+    WriteError(#[from] std::io::Error),
+
+    #[error("Error parsing the stacks: {0}")]
+    ParseError(#[from] toml::de::Error),
+
+    #[error("Error serializing the stacks: {0}")]
+    SerializeError(#[from] toml::ser::Error),
 }
 
 pub type StackResult<T> = std::result::Result<T, StackError>;
@@ -47,6 +78,15 @@ pub enum PackageError{
 
     #[error("System time error: {0}")]
     SystemTimeError(#[from] std::time::SystemTimeError),
+
+    #[error("Process Error: {0}")]
+    ProcessError(#[from] ProcessError),
+
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    #[error("Command failed: {0}")]
+    CommandFailed(String),
 }
 
 pub type PackageResult<T> = std::result::Result<T, PackageError>;
@@ -87,11 +127,53 @@ pub enum RepositoryError{
     #[error("Package Error: {0}")]
     PackageError(#[from] PackageError),
 
+    #[error("Cannot resolve packages:\n{0}")]
+    ResolutionError(ResolutionIssues),
+
+    #[error("Repository is read only: {0}")]
+    ReadOnly(String),
+
+    #[error("Error reading the remote repository: {0}")]
+    RemoteError(String),
+
+    #[error("Repository is locked: {0}")]
+    Locked(String),
+
+    #[error("Invalid channel: {0}")]
+    InvalidChannel(String),
+
+    #[error("{0} is still used, remove it anyway with --force:\n{1}")]
+    InUse(String, String),
+
 }
 
 pub type RepositoryResult<T> = std::result::Result<T, RepositoryError>;
 
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ResolutionIssue{
+    #[error("package `{}` is not in the repository", .0.name)]
+    MissingPackage(PackageName),
+
+    #[error("`{0}` has no matching version in the repository")]
+    MissingVersion(PackageName),
+
+    #[error("cannot read the manifest of `{0}`: {1}")]
+    UnreadableManifest(PackageName, String),
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolutionIssues(pub Vec<ResolutionIssue>);
+
+impl std::fmt::Display for ResolutionIssues{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(|issue| format!("  - {}", issue)).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+
 #[derive(Error, Debug)]
 pub enum GitError{
     #[error("Error initializing the repository: {0}")]
@@ -111,4 +193,64 @@ pub enum GitError{
 }
 
 
-pub type GitResult<T> = std::result::Result<T, GitError>;
\ No newline at end of file
+pub type GitResult<T> = std::result::Result<T, GitError>;
+
+
+#[derive(Error, Debug)]
+pub enum ProcessError{
+    #[error("Error reading the process state: {0}")]
+    StateError(String),
+
+    #[error("No process with id {0}")]
+    ProcessNotFound(u64),
+
+    #[error("Process {0} is not running")]
+    NotRunning(u64),
+
+    #[error("Error killing the process: {0}")]
+    KillError(String),
+
+    #[error("Error writing the process state: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Error parsing the process state: {0}")]
+    ParseError(#[from] toml::de::Error),
+
+    #[error("Error serializing the process state: {0}")]
+    SerializeError(#[from] toml::ser::Error),
+}
+
+pub type ProcessResult<T> = std::result::Result<T, ProcessError>;
+
+
+#[derive(Error, Debug)]
+pub enum SuiteError{
+    #[error("Suite not found: {0}")]
+    SuiteNotFound(String),
+
+    #[error("Invalid suite: {0}")]
+    InvalidSuite(String),
+
+    #[error("Package not found: {0}")]
+    PackageNotFound(String),
+
+    #[error("Error loading the config: {0}")]
+    ConfigError(String),
+
+    #[error("Error writing the suite: {0}")]
+    WriteError(#[from] std::io::Error),
+
+    #[error("Error parsing the suite: {0}")]
+    ParseError(#[from] toml::de::Error),
+
+    #[error("Error serializing the suite: {0}")]
+    SerializeError(#[from] toml::ser::Error),
+
+    #[error("Package Error: {0}")]
+    PackageError(#[from] PackageError),
+
+    #[error("Repository Error: {0}")]
+    RepositoryError(#[from] RepositoryError),
+}
+
+pub type SuiteResult<T> = std::result::Result<T, SuiteError>;
\ No newline at end of file
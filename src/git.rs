@@ -6,7 +6,7 @@ use std::fs;
 use semver::Version;
 use std::process::Command;
 use git2::Repository;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::Write;
 
 pub struct Git{
@@ -133,45 +133,39 @@ impl Git{
     }
 
 
-    pub fn zip_tag(&self, package_version: Version, package_path: PathBuf, repository_path: PathBuf) -> GitResult<()>{
+    /// Archive the tagged version with `git archive` into the temp directory
+    /// and return the zip path, the caller is responsible for removing it.
+    pub fn archive_tag(&self, package_version: &Version, package_path: &Path) -> GitResult<PathBuf>{
 
-        let zip_file_name = format!("{}-package.zip", &package_version);
+        let zip_file_path = std::env::temp_dir().join(format!("vat-{}-{}-package.zip", std::process::id(), package_version));
 
-        Console::info(&format!("Zipping package version: {}", &package_version));
-        Console::dim(&format!("This might take a while..."));
+        Console::info(&format!("Zipping package version: {}", package_version));
+        Console::dim("This might take a while...");
 
         // create zip from git version
         // "git archive --format=zip -o archive.zip 0.0.3"
-        let _command = std::process::Command::new("git")
+        let status = std::process::Command::new("git")
             .arg("archive")
             .arg("--format=zip")
             .arg("-o")
-            .arg(&zip_file_name)
+            .arg(&zip_file_path)
             .arg(package_version.to_string())
-            .current_dir(&package_path)
-            .status()
-            .expect("Failed to create zip file");
-
-        // copy zip file to repository
-        let mut copy_options = fs_extra::dir::CopyOptions::new();
-        copy_options.overwrite = true;
-        copy_options.copy_inside = true;
-
-        if !repository_path.exists(){
-            fs::create_dir_all(&repository_path)?;
+            .current_dir(package_path)
+            .status();
+        match status{
+            Ok(status) if status.success() => Ok(zip_file_path),
+            result => {
+                // git may leave a truncated archive behind
+                if zip_file_path.exists(){
+                    fs::remove_file(&zip_file_path)?;
+                }
+                let reason = match result{
+                    Ok(status) => status.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                Err(GitError::CommandError(format!("git archive {} failed: {}", package_version, reason)))
+            }
         }
-
-        let source_zip_file_path = package_path.join(zip_file_name);
-        let repo_package_version_path = repository_path.join(package_version.to_string());
-
-        let file = std::fs::File::open(&source_zip_file_path)?;
-        let mut archive = zip::read::ZipArchive::new(file)?;
-        archive.extract(repo_package_version_path)?;
-
-        // clean up the source zip file
-        std::fs::remove_file(&source_zip_file_path)?;
-
-        Ok(())
     }
 }
 
@@ -7,9 +7,23 @@ pub mod errors;
 pub mod git;
 pub mod console;
 pub mod repository;
+pub mod backend;
+pub mod remote;
+pub mod lock;
+pub mod atomic;
+pub mod verify;
+pub mod store;
+pub mod audit;
+pub mod dependents;
+pub mod retention;
 pub mod config;
 pub mod variables;
 pub mod stack;
+pub mod process;
+pub mod plan;
+pub mod suite;
+pub mod stack_export;
+pub mod desktop;
 
 pub use package::*;
 pub use environment::*;
@@ -19,6 +33,20 @@ pub use dependencies::*;
 pub use git::*;
 pub use console::*;
 pub use repository::*;
+pub use backend::*;
+pub use remote::*;
+pub use lock::*;
+pub use atomic::*;
+pub use verify::*;
+pub use store::*;
+pub use audit::*;
+pub use dependents::*;
+pub use retention::*;
 pub use config::*;
 pub use variables::*;
-pub use stack::*;
\ No newline at end of file
+pub use stack::*;
+pub use process::*;
+pub use plan::*;
+pub use suite::*;
+pub use stack_export::*;
+pub use desktop::*;
\ No newline at end of file
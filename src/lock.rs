@@ -0,0 +1,283 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::console::Console;
+use crate::errors::{RepositoryError, RepositoryResult};
+use crate::process::is_pid_running;
+
+// how often the holder refreshes the lock, and how long a lock that was not
+// refreshed is trusted
+const HEARTBEAT: Duration = Duration::from_secs(30);
+const STALE_AFTER: Duration = Duration::from_secs(300);
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+
+/// Who holds a lock file, written into it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockHolder{
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+    pub acquired_at: DateTime<Utc>,
+    pub refreshed_at: DateTime<Utc>,
+}
+
+impl LockHolder{
+    pub fn current() -> Self{
+        let now = Utc::now();
+        Self{
+            user: current_user(),
+            host: current_host(),
+            pid: std::process::id(),
+            acquired_at: now,
+            refreshed_at: now,
+        }
+    }
+
+    /// A holder on this host whose process is gone, or one that stopped
+    /// refreshing the lock.
+    pub fn is_stale(&self) -> bool{
+        if self.host == current_host() && !is_pid_running(self.pid){
+            return true;
+        }
+        let age = Utc::now().signed_duration_since(self.refreshed_at);
+        age.to_std().map(|age| age > STALE_AFTER).unwrap_or(false)
+    }
+
+    /// The same acquisition, refreshes aside.
+    pub fn is_same(&self, other: &LockHolder) -> bool{
+        self.pid == other.pid && self.host == other.host && self.acquired_at == other.acquired_at
+    }
+
+    fn read(path: &Path) -> Option<LockHolder>{
+        let toml_string = fs::read_to_string(path).ok()?;
+        toml::from_str(&toml_string).ok()
+    }
+}
+
+impl std::fmt::Display for LockHolder{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "locked by {}@{} since {} (pid {})",
+            self.user,
+            self.host,
+            self.acquired_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+            self.pid)
+    }
+}
+
+
+/// A lock file created with `create_new`, which unlike `flock` is reliable
+/// on NFS. It is removed when dropped.
+pub struct LockFile{
+    pub path: PathBuf,
+    pub holder: LockHolder,
+    stop: Arc<AtomicBool>,
+    // set by the heartbeat once another process took the lock over
+    lost: Arc<AtomicBool>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl LockFile{
+    /// Wait up to `timeout` for the lock, taking over stale ones.
+    pub fn acquire(path: &Path, timeout: Duration) -> RepositoryResult<LockFile>{
+        let started = Instant::now();
+        let mut announced = false;
+        loop{
+            let holder = LockHolder::current();
+            match try_create(path, &holder){
+                Ok(()) => return Ok(LockFile::hold(path, holder)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let current = LockHolder::read(path);
+            let stale = match &current{
+                Some(current) => current.is_stale(),
+                // unreadable, only trust it while it is recent
+                None => modified_age(path).map(|age| age > STALE_AFTER).unwrap_or(false),
+            };
+            if stale{
+                let description = current.as_ref().map(|current| current.to_string()).unwrap_or_else(|| "unreadable lock".to_string());
+                if break_lock(path, current.as_ref())?{
+                    Console::warn(&format!("Removed stale lock {}: {}", path.display(), description));
+                }
+                continue;
+            }
+
+            let description = current.map(|current| current.to_string()).unwrap_or_else(|| "locked".to_string());
+            if started.elapsed() >= timeout{
+                return Err(RepositoryError::Locked(format!("{}, gave up after {}s", description, timeout.as_secs())));
+            }
+            if !announced{
+                Console::info(&format!("Waiting for the repository, {}", description));
+                announced = true;
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    fn hold(path: &Path, holder: LockHolder) -> LockFile{
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicBool::new(false));
+        let heartbeat = {
+            let path = path.to_path_buf();
+            let holder = holder.clone();
+            let stop = stop.clone();
+            let lost = lost.clone();
+            std::thread::spawn(move || {
+                let mut last_refresh = Instant::now();
+                while !stop.load(Ordering::Relaxed){
+                    std::thread::sleep(Duration::from_millis(200));
+                    if last_refresh.elapsed() < HEARTBEAT{
+                        continue;
+                    }
+                    // a stall past STALE_AFTER lets another process break
+                    // the lock, refreshing now would overwrite its lock
+                    let current = LockHolder::read(&path);
+                    if !current.as_ref().is_some_and(|current| current.is_same(&holder)){
+                        lost.store(true, Ordering::Relaxed);
+                        let by = current.map(|current| current.to_string()).unwrap_or_else(|| "removed".to_string());
+                        Console::error(&format!("Lost the lock {}, it is now {}", path.display(), by));
+                        break;
+                    }
+                    let refreshed = LockHolder{ refreshed_at: Utc::now(), ..holder.clone() };
+                    if let Err(e) = refresh(&path, &refreshed){
+                        Console::warn(&format!("Failed to refresh the lock {}: {}", path.display(), e));
+                    }
+                    last_refresh = Instant::now();
+                }
+            })
+        };
+        LockFile{ path: path.to_path_buf(), holder, stop, lost, heartbeat: Some(heartbeat) }
+    }
+
+    /// Whether the lock file still names this holder, checked on disk.
+    pub fn is_held(&self) -> bool{
+        held(&self.path, &self.holder, &self.lost)
+    }
+
+    /// `is_held` for use once the lock is moved into a guard.
+    pub fn held_check(&self) -> impl Fn() -> bool + Send + Sync + 'static{
+        let path = self.path.clone();
+        let holder = self.holder.clone();
+        let lost = self.lost.clone();
+        move || held(&path, &holder, &lost)
+    }
+}
+
+fn held(path: &Path, holder: &LockHolder, lost: &AtomicBool) -> bool{
+    !lost.load(Ordering::Relaxed)
+        && LockHolder::read(path).is_some_and(|current| current.is_same(holder))
+}
+
+impl Drop for LockFile{
+    fn drop(&mut self){
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(heartbeat) = self.heartbeat.take(){
+            let _ = heartbeat.join();
+        }
+        // only remove the file while it is still ours
+        if self.is_held(){
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+
+fn try_create(path: &Path, holder: &LockHolder) -> std::io::Result<()>{
+    let toml_string = toml::to_string(holder).map_err(std::io::Error::other)?;
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(toml_string.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Rewrite the lock through a rename so readers never see a partial file.
+fn refresh(path: &Path, holder: &LockHolder) -> std::io::Result<()>{
+    let temp_path = path.with_extension(format!("lock.{}", holder.pid));
+    fs::write(&temp_path, toml::to_string(holder).map_err(std::io::Error::other)?)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Move the stale lock out of the way. When another process replaced it in
+/// the meantime, its lock is put back. Returns whether the stale lock was
+/// removed.
+fn break_lock(path: &Path, stale: Option<&LockHolder>) -> RepositoryResult<bool>{
+    let moved_path = path.with_extension(format!("stale.{}", std::process::id()));
+    match fs::rename(path, &moved_path){
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    let moved = LockHolder::read(&moved_path);
+    if moved.as_ref() != stale{
+        // hard_link fails instead of replacing a lock taken since
+        let _ = fs::hard_link(&moved_path, path);
+        fs::remove_file(&moved_path)?;
+        return Ok(false);
+    }
+    fs::remove_file(&moved_path)?;
+    Ok(true)
+}
+
+fn modified_age(path: &Path) -> Option<Duration>{
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+
+pub fn current_user() -> String{
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+pub fn current_host() -> String{
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0{
+        return "unknown".to_string();
+    }
+    let end = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).to_string()
+}
+
+#[cfg(windows)]
+pub fn current_host() -> String{
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn taken_over_lock_is_not_held_nor_removed(){
+        let dir = std::env::temp_dir().join(format!("vat-lock-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.lock");
+
+        let lock_file = LockFile::acquire(&path, Duration::from_secs(1)).unwrap();
+        assert!(lock_file.is_held());
+        let check = lock_file.held_check();
+
+        // another host broke the lock after a stall
+        let other = LockHolder{ host: "elsewhere".to_string(), pid: 1, ..LockHolder::current() };
+        refresh(&path, &other).unwrap();
+        assert!(!lock_file.is_held());
+        assert!(!check());
+
+        drop(lock_file);
+        assert_eq!(LockHolder::read(&path).map(|holder| holder.host), Some("elsewhere".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
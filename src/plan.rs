@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Limits;
+use crate::console::Console;
+use crate::Vat;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Launcher{
+    Inline,
+    Detached,
+    // a new terminal window, like a desktop launcher would open
+    Terminal,
+}
+
+impl Launcher{
+    pub fn new(detach: bool, terminal: bool) -> Self{
+        if terminal{
+            Launcher::Terminal
+        }else if detach{
+            Launcher::Detached
+        }else{
+            Launcher::Inline
+        }
+    }
+}
+
+impl std::fmt::Display for Launcher{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Launcher::Inline => write!(f, "inline"),
+            Launcher::Detached => write!(f, "detached"),
+            Launcher::Terminal => write!(f, "terminal"),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolvedPackage{
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+}
+
+impl ResolvedPackage{
+    pub fn from_vat(vat: &Vat) -> Self{
+        Self{
+            name: vat.package.name.clone(),
+            version: vat.package.version.to_string(),
+            path: vat.package_path.clone(),
+        }
+    }
+}
+
+
+/// Everything `vat run` would do, without spawning anything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunPlan{
+    // main package first, then the appended ones in resolution order
+    pub packages: Vec<ResolvedPackage>,
+    pub command: String,
+    pub argv: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub launcher: Launcher,
+    pub limits: Limits,
+    // full environment the process would start with
+    pub env: BTreeMap<String, String>,
+}
+
+impl RunPlan{
+    pub fn print(&self){
+        Console::bold(&format!("Dry run: {}", self.command));
+        Console::info("Packages:");
+        for package in &self.packages{
+            Console::resolved_env(&format!("  {}/{}", package.name, package.version), &package.path.to_string_lossy());
+        }
+        Console::resolved_env("Argv", &format!("{:?}", self.argv));
+        let cwd = self.cwd.as_ref().map(|cwd| cwd.to_string_lossy().to_string()).unwrap_or_else(|| "<current directory>".to_string());
+        Console::resolved_env("Cwd", &cwd);
+        Console::resolved_env("Launcher", &self.launcher.to_string());
+        if !self.limits.is_empty(){
+            Console::resolved_env("Limits", &format!("{:?}", self.limits));
+        }
+        Console::info("Environment:");
+        for (key, value) in &self.env{
+            Console::resolved_env(&format!("  {}", key), value);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+
+use crate::atomic::write_atomic;
+use crate::config::VatConfig;
+use crate::console::Console;
+use crate::errors::{ProcessError, ProcessResult};
+
+const PROCESS_REGISTRY_FILE: &str = "processes.toml";
+const PROCESS_REGISTRY_LOCK_FILE: &str = "processes.toml.lock";
+// exited sessions (and their logs) kept around for `vat logs`
+const MAX_EXITED_PROCESSES: usize = 50;
+// a log past this size is cut down to its last LOG_KEEP_SIZE bytes
+const MAX_LOG_SIZE: u64 = 16 * 1024 * 1024;
+const LOG_KEEP_SIZE: u64 = 8 * 1024 * 1024;
+const LOG_TRIMMED: &str = "[vat: earlier output was dropped to keep the log small]\n";
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessRecord{
+    pub id: u64,
+    pub pid: u32,
+    pub package: String,
+    pub version: String,
+    pub command: String,
+    pub argv: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub started_at: DateTime<Utc>,
+    // when the OS started the pid, tells a reused pid apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    // packages appended to the environment
+    pub context: Vec<String>,
+    pub log_path: PathBuf,
+}
+
+impl ProcessRecord{
+    /// Whether the recorded process still runs. A pid now used by another
+    /// process does not count, records without a start time trust the pid.
+    pub fn is_running(&self) -> bool{
+        if !is_pid_running(self.pid){
+            return false;
+        }
+        match &self.start_time{
+            Some(start_time) => process_start_time(self.pid).as_ref() == Some(start_time),
+            None => true,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessRegistry{
+    pub next_id: u64,
+    pub processes: Vec<ProcessRecord>,
+    #[serde(skip)]
+    pub state_path: PathBuf,
+}
+
+impl Default for ProcessRegistry{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl ProcessRegistry{
+    pub fn new() -> Self{
+        Self{
+            next_id: 1,
+            processes: Vec::new(),
+            state_path: PathBuf::new(),
+        }
+    }
+
+    pub fn load() -> ProcessResult<Self>{
+        let state_path = VatConfig::get_state_dir()
+            .ok_or_else(|| ProcessError::StateError("Failed to get the state directory".to_string()))?;
+        fs::create_dir_all(state_path.join("logs"))?;
+
+        let registry_path = state_path.join(PROCESS_REGISTRY_FILE);
+        // written atomically, readers never see a partial file
+        let mut registry = if registry_path.exists(){
+            toml::from_str(&fs::read_to_string(&registry_path)?)?
+        }else{
+            ProcessRegistry::new()
+        };
+        registry.state_path = state_path;
+        Ok(registry)
+    }
+
+    /// Exclusive lock for a load, change and save cycle, released on drop.
+    /// Held from `load` through `register` so concurrent launches get
+    /// distinct ids and keep each other's records.
+    pub fn lock() -> ProcessResult<File>{
+        let state_path = VatConfig::get_state_dir()
+            .ok_or_else(|| ProcessError::StateError("Failed to get the state directory".to_string()))?;
+        fs::create_dir_all(&state_path)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(state_path.join(PROCESS_REGISTRY_LOCK_FILE))?;
+        FileExt::lock_exclusive(&file)?;
+        Ok(file)
+    }
+
+    pub fn save(&self) -> ProcessResult<()>{
+        let toml_string = toml::to_string(self)?;
+        write_atomic(&self.state_path.join(PROCESS_REGISTRY_FILE), toml_string.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// Reserve the next id and open a fresh log file for it.
+    pub fn create_log(&mut self) -> ProcessResult<(u64, PathBuf, File)>{
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let log_path = self.state_path.join("logs").join(format!("{}.log", id));
+        // appending, so trimming the log does not leave a gap before new output
+        let log_file = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        log_file.set_len(0)?;
+        Ok((id, log_path, log_file))
+    }
+
+    pub fn register(&mut self, record: ProcessRecord) -> ProcessResult<()>{
+        self.processes.push(record);
+        self.rotate()?;
+        self.save()
+    }
+
+    /// Drop the oldest exited sessions and their logs, and cut down the logs
+    /// that grew past `MAX_LOG_SIZE`.
+    pub fn rotate(&mut self) -> ProcessResult<()>{
+        self.trim_logs();
+
+        let exited: Vec<u64> = self.processes.iter()
+            .filter(|record| !record.is_running())
+            .map(|record| record.id)
+            .collect();
+
+        if exited.len() <= MAX_EXITED_PROCESSES{
+            return Ok(());
+        }
+
+        let remove = &exited[..exited.len() - MAX_EXITED_PROCESSES];
+        for record in self.processes.iter().filter(|record| remove.contains(&record.id)){
+            if record.log_path.exists(){
+                fs::remove_file(&record.log_path)?;
+            }
+        }
+        self.processes.retain(|record| !remove.contains(&record.id));
+        Ok(())
+    }
+
+    pub fn get(&self, id: u64) -> ProcessResult<&ProcessRecord>{
+        self.processes.iter()
+            .find(|record| record.id == id)
+            .ok_or(ProcessError::ProcessNotFound(id))
+    }
+
+    /// Keep the end of oversized logs of running sessions. Output written
+    /// while a log is trimmed may be lost.
+    pub fn trim_logs(&self){
+        for record in self.processes.iter().filter(|record| record.is_running()){
+            if let Err(e) = trim_log(&record.log_path){
+                Console::warn(&format!("Failed to trim {}: {}", record.log_path.display(), e));
+            }
+        }
+    }
+
+    pub fn list(&self, all: bool) -> Vec<&ProcessRecord>{
+        self.processes.iter()
+            .filter(|record| all || record.is_running())
+            .collect()
+    }
+
+    pub fn kill(&self, id: u64, force: bool) -> ProcessResult<()>{
+        let record = self.get(id)?;
+        if !record.is_running(){
+            return Err(ProcessError::NotRunning(id));
+        }
+        kill_pid(record.pid, force)
+    }
+
+    /// Write the log of a session to `out`. With `follow` keep streaming new
+    /// output until the process exits.
+    pub fn logs(&self, id: u64, follow: bool, out: &mut dyn Write) -> ProcessResult<()>{
+        let record = self.get(id)?;
+        let mut file = File::open(&record.log_path)?;
+        let mut position = 0;
+        loop{
+            // trimmed since the last read, what is left was already shown
+            let length = file.metadata()?.len();
+            if length < position{
+                position = length;
+            }
+            file.seek(SeekFrom::Start(position))?;
+            let mut buffer = Vec::new();
+            position += file.read_to_end(&mut buffer)? as u64;
+            out.write_all(&buffer)?;
+            out.flush()?;
+
+            if !follow{
+                return Ok(());
+            }
+            if position > MAX_LOG_SIZE && record.is_running(){
+                trim_log(&record.log_path)?;
+                position = file.metadata()?.len();
+            }
+            if buffer.is_empty(){
+                if !record.is_running(){
+                    return Ok(());
+                }
+                std::thread::sleep(Duration::from_millis(250));
+            }
+        }
+    }
+}
+
+
+fn trim_log(log_path: &std::path::Path) -> std::io::Result<()>{
+    let mut file = OpenOptions::new().read(true).write(true).open(log_path)?;
+    let length = file.metadata()?.len();
+    if length <= MAX_LOG_SIZE{
+        return Ok(());
+    }
+    file.seek(SeekFrom::Start(length - LOG_KEEP_SIZE))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(LOG_TRIMMED.as_bytes())?;
+    file.write_all(&tail)?;
+    Ok(())
+}
+
+
+/// When the OS started `pid`, compared to tell a reused pid apart.
+#[cfg(target_os = "linux")]
+pub fn process_start_time(pid: u32) -> Option<String>{
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name before `)` may contain spaces, starttime is the 22nd field
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    fields.get(19).map(|start_time| start_time.to_string())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn process_start_time(pid: u32) -> Option<String>{
+    let output = std::process::Command::new("ps")
+        .args(["-o", "lstart=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    let start_time = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if start_time.is_empty(){ None }else{ Some(start_time) }
+}
+
+#[cfg(windows)]
+pub fn process_start_time(pid: u32) -> Option<String>{
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-Command", &format!("(Get-Process -Id {}).StartTime.ToFileTimeUtc()", pid)])
+        .output()
+        .ok()?;
+    let start_time = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || start_time.is_empty(){ None }else{ Some(start_time) }
+}
+
+#[cfg(unix)]
+pub fn is_pid_running(pid: u32) -> bool{
+    // signal 0 only checks that the process exists, EPERM means it belongs
+    // to another user
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub fn is_pid_running(pid: u32) -> bool{
+    let output = std::process::Command::new("tasklist")
+        .arg("/FI")
+        .arg(format!("PID eq {}", pid))
+        .arg("/NH")
+        .arg("/FO")
+        .arg("CSV")
+        .output();
+    // `"image.exe","1234",...`, the filter prints an info line when nothing matches
+    let pid = format!("\"{}\"", pid);
+    match output{
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.split(',').nth(1) == Some(pid.as_str())),
+        Err(_) => false,
+    }
+}
+
+#[cfg(unix)]
+pub fn kill_pid(pid: u32, force: bool) -> ProcessResult<()>{
+    let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+    let pid = pid as libc::pid_t;
+    // detached processes lead their own process group, take the children
+    // with them. Only a group the process leads, never one it merely joined.
+    let target = if unsafe { libc::getpgid(pid) } == pid { -pid } else { pid };
+    if unsafe { libc::kill(target, signal) } != 0{
+        return Err(ProcessError::KillError(std::io::Error::last_os_error().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn kill_pid(pid: u32, force: bool) -> ProcessResult<()>{
+    let mut cmd = std::process::Command::new("taskkill");
+    cmd.arg("/PID").arg(pid.to_string()).arg("/T");
+    if force{
+        cmd.arg("/F");
+    }
+    let status = cmd.status()?;
+    if !status.success(){
+        return Err(ProcessError::KillError(format!("taskkill exited with {}", status)));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn record(start_time: Option<String>) -> ProcessRecord{
+        ProcessRecord{
+            id: 1,
+            pid: std::process::id(),
+            package: "pkg".to_string(),
+            version: "1.0.0".to_string(),
+            command: "run".to_string(),
+            argv: Vec::new(),
+            cwd: None,
+            started_at: Utc::now(),
+            start_time,
+            context: Vec::new(),
+            log_path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn reused_pids_are_not_running(){
+        let start_time = process_start_time(std::process::id());
+        assert!(start_time.is_some());
+        assert!(record(start_time).is_running());
+        assert!(record(None).is_running());
+        assert!(!record(Some("0".to_string())).is_running());
+    }
+
+    #[test]
+    fn oversized_logs_keep_their_end(){
+        let log_path = std::env::temp_dir().join(format!("vat-process-test-{}.log", std::process::id()));
+        let mut content = vec![b'a'; (MAX_LOG_SIZE - LOG_KEEP_SIZE) as usize];
+        content.extend(vec![b'b'; LOG_KEEP_SIZE as usize]);
+        fs::write(&log_path, &content).unwrap();
+        trim_log(&log_path).unwrap();
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), MAX_LOG_SIZE);
+
+        content.push(b'c');
+        fs::write(&log_path, &content).unwrap();
+        trim_log(&log_path).unwrap();
+        let trimmed = fs::read(&log_path).unwrap();
+        assert!(trimmed.starts_with(LOG_TRIMMED.as_bytes()));
+        assert_eq!(trimmed.len() as u64, LOG_TRIMMED.len() as u64 + LOG_KEEP_SIZE);
+        assert!(trimmed.ends_with(b"bc"));
+        fs::remove_file(&log_path).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use semver::Version;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::audit::AuditEntry;
+use crate::backend::{RepositoryBackend, RepositoryLock};
+use crate::console::Console;
+use crate::errors::{RepositoryError, RepositoryResult};
+use crate::repository::{Notice, PackageRegistry, RepoPackage, Repository};
+
+pub const STATIC_INDEX_FILE: &str = "vat_index.toml";
+const ARCHIVES_DIR: &str = "archives";
+// fetches started by this process, names their partial directories
+static FETCHES: AtomicU64 = AtomicU64::new(0);
+
+
+/// One published version as listed in a static index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexedVersion{
+    pub version: Version,
+    pub repository: Option<Url>,
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yanked: Option<Notice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<Notice>,
+    // relative to the index
+    pub archive: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+
+/// The files a remote repository serves: `vat_index.toml` and one zip per
+/// version under `archives/<name>/<version>.zip`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticIndex{
+    pub generated_at: DateTime<Utc>,
+    pub packages: BTreeMap<String, Vec<IndexedVersion>>,
+    // channels of each package
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, BTreeMap<String, Version>>,
+    // packages whose `latest` skips pre-release versions
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub stable_latest: BTreeSet<String>,
+}
+
+impl StaticIndex{
+    /// Write the index and archives of every published version of
+    /// `repository` to `output`. Linked main branches are not exported.
+    pub fn build(repository: &Repository, output: &Path) -> RepositoryResult<StaticIndex>{
+        let mut packages: BTreeMap<String, Vec<IndexedVersion>> = BTreeMap::new();
+        let mut channels = BTreeMap::new();
+        let mut stable_latest = BTreeSet::new();
+        for (package_name, package_registry) in &repository.packages{
+            if !package_registry.channels.is_empty(){
+                channels.insert(package_name.clone(), package_registry.channels.clone());
+            }
+            if package_registry.stable_latest{
+                stable_latest.insert(package_name.clone());
+            }
+            let mut versions: Vec<&RepoPackage> = package_registry.versions.values().collect();
+            versions.sort_by(|a, b| a.version.cmp(&b.version));
+            for repo_package in versions{
+                let archive = archive_path(package_name, &repo_package.version);
+                let archive_file = output.join(&archive);
+                if let Some(parent) = archive_file.parent(){
+                    fs::create_dir_all(parent)?;
+                }
+                zip_dir(&repo_package.package_path, &archive_file)?;
+                let bytes = fs::read(&archive_file)?;
+                packages.entry(package_name.clone()).or_default().push(IndexedVersion{
+                    version: repo_package.version.clone(),
+                    repository: repo_package.repository.clone(),
+                    message: repo_package.message.clone(),
+                    published_at: repo_package.published_at,
+                    yanked: repo_package.yanked.clone(),
+                    deprecated: repo_package.deprecated.clone(),
+                    archive,
+                    sha256: sha256_hex(&bytes),
+                    size: bytes.len() as u64,
+                });
+            }
+        }
+
+        let index = StaticIndex{ generated_at: Utc::now(), packages, channels, stable_latest };
+        fs::write(output.join(STATIC_INDEX_FILE), toml::to_string(&index)?)?;
+        Ok(index)
+    }
+
+    fn find(&self, name: &str, version: &Version) -> Option<&IndexedVersion>{
+        self.packages.get(name)?.iter().find(|indexed| &indexed.version == version)
+    }
+}
+
+
+/// Read only repository served over plain http. The index and the versions
+/// that get run are cached in `cache_path`.
+#[derive(Debug)]
+pub struct HttpBackend{
+    pub url: Url,
+    pub cache_path: PathBuf,
+    index: Mutex<Option<StaticIndex>>,
+}
+
+impl HttpBackend{
+    pub fn new(url: Url, cache_path: PathBuf) -> Self{
+        // without the trailing slash `join` would replace the last segment
+        let mut url = url;
+        if !url.path().ends_with('/'){
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Self{ url, cache_path, index: Mutex::new(None) }
+    }
+
+    fn get(&self, path: &str) -> RepositoryResult<Vec<u8>>{
+        check_scheme(&self.url)?;
+        let url = self.url.join(path).map_err(|e| RepositoryError::RemoteError(e.to_string()))?;
+        let response = ureq::get(url.as_str()).call()
+            .map_err(|e| RepositoryError::RemoteError(format!("{}: {}", url, e)))?;
+        let mut bytes = Vec::new();
+        response.into_reader().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// The served index, or the cached copy when the server cannot be reached.
+    fn fetch_index(&self) -> RepositoryResult<StaticIndex>{
+        let cached_index = self.cache_path.join(STATIC_INDEX_FILE);
+        let toml_string = match self.get(STATIC_INDEX_FILE){
+            Ok(bytes) => {
+                let toml_string = String::from_utf8(bytes).map_err(|e| RepositoryError::RemoteError(e.to_string()))?;
+                fs::create_dir_all(&self.cache_path)?;
+                fs::write(&cached_index, &toml_string)?;
+                toml_string
+            }
+            Err(e) if cached_index.exists() => {
+                Console::warn(&format!("{}, using the cached index", e));
+                fs::read_to_string(&cached_index)?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(toml::from_str(&toml_string)?)
+    }
+
+    /// Refuses paths that would leave the cache.
+    fn check_cached(&self, path: &Path) -> RepositoryResult<()>{
+        let inside = path.strip_prefix(&self.cache_path)
+            .is_ok_and(|relative| relative.components().all(|component| matches!(component, std::path::Component::Normal(_))));
+        if !inside{
+            return Err(RepositoryError::RemoteError(format!("{} is outside the cache {}", path.display(), self.cache_path.display())));
+        }
+        Ok(())
+    }
+
+    fn read_only(&self) -> RepositoryError{
+        RepositoryError::ReadOnly(format!("{} is a remote repository", self.url))
+    }
+}
+
+impl RepositoryBackend for HttpBackend{
+    fn location(&self) -> String{
+        self.url.to_string()
+    }
+
+    fn index_exists(&self) -> bool{
+        self.get(STATIC_INDEX_FILE).is_ok()
+    }
+
+    fn read_index(&self) -> RepositoryResult<HashMap<String, PackageRegistry>>{
+        let index = self.fetch_index()?;
+        let mut packages = HashMap::new();
+        for (package_name, versions) in &index.packages{
+            // the name becomes a directory of the cache
+            if !is_safe_name(package_name){
+                return Err(RepositoryError::RemoteError(format!("{} lists an invalid package name `{}`", self.url, package_name)));
+            }
+            let mut package_registry = PackageRegistry::new();
+            package_registry.channels = index.channels.get(package_name).cloned().unwrap_or_default();
+            package_registry.stable_latest = index.stable_latest.contains(package_name);
+            for indexed in versions{
+                package_registry.add_package(RepoPackage{
+                    name: package_name.clone(),
+                    version: indexed.version.clone(),
+                    package_path: self.cache_path.join(package_name).join(indexed.version.to_string()),
+                    repository: indexed.repository.clone(),
+                    message: indexed.message.clone(),
+                    published_at: indexed.published_at,
+                    yanked: indexed.yanked.clone(),
+                    deprecated: indexed.deprecated.clone(),
+                    manifest: BTreeMap::new(),
+                    source: String::new(),
+                });
+            }
+            packages.insert(package_name.clone(), package_registry);
+        }
+        *self.index.lock().unwrap() = Some(index);
+        Ok(packages)
+    }
+
+    fn write_index(&self, _packages: &HashMap<String, PackageRegistry>) -> RepositoryResult<()>{
+        Err(self.read_only())
+    }
+
+    fn store_payload(&self, _name: &str, _version: &Version, _archive: &Path) -> RepositoryResult<PathBuf>{
+        Err(self.read_only())
+    }
+
+    fn remove_payload(&self, _name: &str, _version: Option<&Version>) -> RepositoryResult<()>{
+        Err(self.read_only())
+    }
+
+    fn append_audit(&self, _entry: &AuditEntry) -> RepositoryResult<()>{
+        Err(self.read_only())
+    }
+
+    /// The audit log is not part of the static index.
+    fn read_audit(&self) -> RepositoryResult<Vec<AuditEntry>>{
+        Ok(Vec::new())
+    }
+
+    fn lock(&self, _timeout: Duration) -> RepositoryResult<RepositoryLock>{
+        Err(self.read_only())
+    }
+
+    /// Download and verify the archive the first time a version is used.
+    fn fetch_payload(&self, package: &RepoPackage) -> RepositoryResult<PathBuf>{
+        self.check_cached(&package.package_path)?;
+        if package.package_path.exists(){
+            return Ok(package.package_path.clone());
+        }
+        let indexed = {
+            let mut index = self.index.lock().unwrap();
+            if index.is_none(){
+                *index = Some(self.fetch_index()?);
+            }
+            index.as_ref().and_then(|index| index.find(&package.name, &package.version)).cloned()
+        };
+        let indexed = indexed.ok_or_else(|| RepositoryError::PackageNotFound(format!("{}/{} is not in {}", package.name, package.version, self.url)))?;
+
+        Console::info(&format!("Downloading {}/{} from {}", package.name, package.version, self.url));
+        let bytes = self.get(&indexed.archive)?;
+        let checksum = sha256_hex(&bytes);
+        if checksum != indexed.sha256{
+            return Err(RepositoryError::RemoteError(format!("Checksum mismatch for {}/{}: expected {}, got {}", package.name, package.version, indexed.sha256, checksum)));
+        }
+
+        // extract next to the target so an interrupted download is never
+        // used, one directory per fetch so concurrent ones don't collide
+        let fetch = FETCHES.fetch_add(1, Ordering::Relaxed);
+        let partial_path = self.cache_path.join(&package.name).join(format!("{}.partial-{}-{}", package.version, std::process::id(), fetch));
+        self.check_cached(&partial_path)?;
+        if partial_path.exists(){
+            fs::remove_dir_all(&partial_path)?;
+        }
+        fs::create_dir_all(&partial_path)?;
+        let extracted = zip::read::ZipArchive::new(Cursor::new(bytes))
+            .and_then(|mut archive| archive.extract(&partial_path))
+            .map_err(|e| RepositoryError::RemoteError(e.to_string()));
+        if let Err(e) = extracted{
+            fs::remove_dir_all(&partial_path)?;
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&partial_path, &package.package_path){
+            fs::remove_dir_all(&partial_path)?;
+            // another process fetched the same version first
+            if !package.package_path.exists(){
+                return Err(e.into());
+            }
+        }
+        Ok(package.package_path.clone())
+    }
+}
+
+
+/// ureq is built without TLS, only plain http can be fetched.
+pub fn check_scheme(url: &Url) -> RepositoryResult<()>{
+    if url.scheme() != "http"{
+        return Err(RepositoryError::RemoteError(format!("{} is not supported, vat is built without TLS, serve the index over plain http", url)));
+    }
+    Ok(())
+}
+
+/// A single path component. Used for names that others decide and that
+/// become file names, like the package names of a remote index.
+pub(crate) fn is_safe_name(name: &str) -> bool{
+    !name.is_empty()
+        && name != "."
+        && !name.contains("..")
+        && !name.contains(['/', '\\', ':'])
+}
+
+
+pub fn archive_path(name: &str, version: &Version) -> String{
+    format!("{}/{}/{}.zip", ARCHIVES_DIR, name, version)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String{
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+
+/// Zip the content of `source`, keeping unix permissions.
+fn zip_dir(source: &Path, target: &Path) -> RepositoryResult<()>{
+    let to_error = |e: zip::result::ZipError| RepositoryError::PublishError(e.to_string());
+    let mut writer = zip::ZipWriter::new(File::create(target)?);
+    let mut pending = vec![source.to_path_buf()];
+    while let Some(dir) = pending.pop(){
+        let mut entries: Vec<_> = fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries{
+            let path = entry.path();
+            let relative = path.strip_prefix(source).unwrap_or(&path)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            let metadata = entry.metadata()?;
+            let options = zip::write::SimpleFileOptions::default().unix_permissions(permissions(&metadata));
+            if metadata.is_dir(){
+                writer.add_directory(relative, options).map_err(to_error)?;
+                pending.push(path);
+            }else{
+                writer.start_file(relative, options).map_err(to_error)?;
+                std::io::copy(&mut File::open(&path)?, &mut writer)?;
+            }
+        }
+    }
+    writer.finish().map_err(to_error)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32{
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32{
+    if metadata.is_dir(){ 0o755 }else{ 0o644 }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// A minimal static file server for `root`, standing in for any web server.
+    fn serve(root: PathBuf) -> Url{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming(){
+                let Ok(mut stream) = stream else { continue };
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                if reader.read_line(&mut request_line).is_err(){
+                    continue;
+                }
+                // skip the headers
+                let mut header = String::new();
+                while reader.read_line(&mut header).is_ok_and(|read| read > 2){
+                    header.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or("/").trim_start_matches('/');
+                let response = match fs::read(root.join(path)){
+                    Ok(body) => {
+                        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                        response.extend(body);
+                        response
+                    }
+                    Err(_) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        Url::parse(&format!("http://{}/repo", address)).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("vat-remote-test-{}-{}", name, std::process::id()));
+        if dir.exists(){
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn published(root: &Path, name: &str, version: &str) -> RepoPackage{
+        let version = Version::parse(version).unwrap();
+        let package_path = root.join(name).join(version.to_string());
+        fs::create_dir_all(package_path.join("bin")).unwrap();
+        fs::write(package_path.join("vat.toml"), format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, version)).unwrap();
+        fs::write(package_path.join("bin").join("tool"), format!("{} {}", name, version)).unwrap();
+        RepoPackage{
+            name: name.to_string(),
+            version,
+            package_path,
+            repository: None,
+            message: Some("release".to_string()),
+            published_at: Some(Utc::now()),
+            yanked: None,
+            deprecated: None,
+            manifest: BTreeMap::new(),
+            source: String::new(),
+        }
+    }
+
+    #[test]
+    fn static_index_round_trip(){
+        let dir = temp_dir("round-trip");
+        let mut repository = Repository::new();
+        let mut package_registry = PackageRegistry::new();
+        package_registry.add_package(published(&dir.join("published"), "tool", "1.0.0"));
+        let mut yanked = published(&dir.join("published"), "tool", "1.1.0");
+        yanked.yanked = Some(Notice::new("broken"));
+        package_registry.add_package(yanked);
+        package_registry.add_package(published(&dir.join("published"), "tool", "2.0.0-rc.1"));
+        package_registry.channels.insert("beta".to_string(), Version::parse("2.0.0-rc.1").unwrap());
+        package_registry.stable_latest = true;
+        repository.packages.insert("tool".to_string(), package_registry.clone());
+
+        let served = dir.join("served").join("repo");
+        fs::create_dir_all(&served).unwrap();
+        let index = StaticIndex::build(&repository, &served).unwrap();
+        assert_eq!(index.packages["tool"].len(), 3);
+        assert!(served.join(archive_path("tool", &Version::parse("1.0.0").unwrap())).exists());
+
+        let backend = HttpBackend::new(serve(dir.join("served")), dir.join("cache"));
+        assert!(backend.index_exists());
+        let packages = backend.read_index().unwrap();
+        let remote = &packages["tool"];
+        assert_eq!(remote.channels, package_registry.channels);
+        assert!(remote.stable_latest);
+        assert_eq!(remote.latest_version(), Some(&Version::parse("1.0.0").unwrap()));
+        assert!(remote.versions[&Version::parse("1.1.0").unwrap()].yanked.is_some());
+
+        let remote_package = &remote.versions[&Version::parse("2.0.0-rc.1").unwrap()];
+        assert!(!remote_package.package_path.exists());
+        let path = backend.fetch_payload(remote_package).unwrap();
+        assert_eq!(path, dir.join("cache").join("tool").join("2.0.0-rc.1"));
+        assert_eq!(fs::read_to_string(path.join("bin").join("tool")).unwrap(), "tool 2.0.0-rc.1");
+        let leftovers: Vec<_> = fs::read_dir(dir.join("cache").join("tool")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.contains("partial"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+
+        // several threads racing for the same version all get it
+        let racing = dir.join("cache").join("tool").join("1.0.0");
+        let remote_package = remote.versions[&Version::parse("1.0.0").unwrap()].clone();
+        std::thread::scope(|scope| {
+            for _ in 0..4{
+                scope.spawn(|| assert_eq!(backend.fetch_payload(&remote_package).unwrap(), racing));
+            }
+        });
+        assert_eq!(fs::read_to_string(racing.join("bin").join("tool")).unwrap(), "tool 1.0.0");
+
+        assert!(matches!(backend.write_index(&packages), Err(RepositoryError::ReadOnly(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn https_is_refused_without_tls(){
+        let backend = HttpBackend::new(Url::parse("https://example.com/repo").unwrap(), std::env::temp_dir());
+        assert!(matches!(backend.read_index(), Err(RepositoryError::RemoteError(e)) if e.contains("without TLS")));
+        assert!(check_scheme(&Url::parse("http://example.com/repo").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn tampered_archives_and_names_are_refused(){
+        let dir = temp_dir("tampered");
+        let mut repository = Repository::new();
+        let mut package_registry = PackageRegistry::new();
+        package_registry.add_package(published(&dir.join("published"), "tool", "1.0.0"));
+        repository.packages.insert("tool".to_string(), package_registry);
+        let served = dir.join("served").join("repo");
+        fs::create_dir_all(&served).unwrap();
+        let mut index = StaticIndex::build(&repository, &served).unwrap();
+
+        let backend = HttpBackend::new(serve(dir.join("served")), dir.join("cache"));
+        let packages = backend.read_index().unwrap();
+        let remote_package = &packages["tool"].versions[&Version::parse("1.0.0").unwrap()];
+        fs::write(served.join(&index.packages["tool"][0].archive), b"not the published archive").unwrap();
+        assert!(matches!(backend.fetch_payload(remote_package), Err(RepositoryError::RemoteError(e)) if e.contains("Checksum mismatch")));
+        assert!(!remote_package.package_path.exists());
+
+        let versions = index.packages.remove("tool").unwrap();
+        index.packages.insert("../../evil".to_string(), versions);
+        fs::write(served.join(STATIC_INDEX_FILE), toml::to_string(&index).unwrap()).unwrap();
+        assert!(matches!(backend.read_index(), Err(RepositoryError::RemoteError(e)) if e.contains("invalid package name")));
+
+        let outside = RepoPackage{ package_path: dir.join("cache").join("..").join("evil"), ..remote_package.clone() };
+        assert!(matches!(backend.fetch_payload(&outside), Err(RepositoryError::RemoteError(e)) if e.contains("outside the cache")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
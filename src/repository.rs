@@ -1,20 +1,26 @@
 use url::Url;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use semver::Version;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use serde::{Deserialize, Serialize};
-use fs2::FileExt;
-use std::fs::OpenOptions;
-use std::io::Write;
+use chrono::{DateTime, Utc};
 
 use crate::console::Console;
 use crate::Vat;
 use crate::Stack;
-use crate::config::VatConfig;
-use crate::errors::{RepositoryError, RepositoryResult};
+use crate::command::Limits;
+use crate::plan::{Launcher, ResolvedPackage, RunPlan};
+use crate::config::{RepositoryConfig, VatConfig, DEFAULT_REPOSITORY};
+use crate::errors::{RepositoryError, RepositoryResult, ResolutionIssue, ResolutionIssues};
 use crate::git::Git;
+use crate::backend::{Backend, FilesystemBackend, LockCheck, RepositoryLock};
+use crate::remote::HttpBackend;
+use crate::verify::{build_manifest, VerifyReport};
+use crate::lock::current_user;
+use crate::audit::AuditEntry;
+use crate::dependents::find_dependents;
+use crate::retention::{GcPlan, Retention};
 
-const VAT_REPOSITORY_FILE: &str = "vat_repository.toml";
 
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -28,7 +34,9 @@ pub struct PackageName{
 pub enum PackageVersion{
     Version(Version),
     Latest,
-    Main
+    Main,
+    // the version a named channel of the package points at, `pkg/@stable`
+    Channel(String),
 }
 
 impl std::fmt::Display for PackageVersion{
@@ -37,10 +45,17 @@ impl std::fmt::Display for PackageVersion{
             PackageVersion::Version(version) => write!(f, "{}", version),
             PackageVersion::Latest => write!(f, "latest"),
             PackageVersion::Main => write!(f, "main"),
+            PackageVersion::Channel(channel) => write!(f, "@{}", channel),
         }
     }
 }
 
+impl std::fmt::Display for PackageName{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.name, self.version)
+    }
+}
+
 impl PackageName{
     pub fn from_str(package_name: &str) -> Self{
         let package_name = package_name.replace(",", "");
@@ -65,6 +80,12 @@ impl PackageName{
                     version: PackageVersion::Main,
                     active: true,
                 }
+            }else if let Some(channel) = part_version.strip_prefix('@'){
+                Self{
+                    name: parts[0].to_string(),
+                    version: PackageVersion::Channel(channel.to_string()),
+                    active: true,
+                }
             }else{
                 let version = Version::parse(&part_version);
                 if version.is_err(){
@@ -102,6 +123,24 @@ pub struct Repository{
     pub packages: HashMap<String, PackageRegistry>,
     #[serde(skip)]
     pub repository_path: PathBuf,
+    // name of the repository in the config's search path
+    #[serde(skip)]
+    pub name: String,
+    // repositories combined into this view, in priority order. A combined
+    // view is read only.
+    #[serde(skip)]
+    pub merged: Vec<String>,
+    // storage of the index and payloads, the filesystem at repository_path
+    // when not set
+    #[serde(skip)]
+    pub backend: Option<Backend>,
+    // backend of each repository in the view, by name
+    #[serde(skip)]
+    pub sources: HashMap<String, Backend>,
+    // lock of the transaction changing this repository, `save` refuses to
+    // write once it was lost
+    #[serde(skip)]
+    pub lock_check: Option<LockCheck>,
 }
 
 impl Repository{
@@ -109,9 +148,32 @@ impl Repository{
         Repository{
             packages: HashMap::new(),
             repository_path: PathBuf::new(),
+            name: DEFAULT_REPOSITORY.to_string(),
+            merged: Vec::new(),
+            backend: None,
+            sources: HashMap::new(),
+            lock_check: None,
         }
     }
 
+    pub fn with_backend(mut self, backend: Backend) -> Repository{
+        self.backend = Some(backend);
+        self
+    }
+
+    pub fn backend(&self) -> Backend{
+        self.backend.clone()
+            .unwrap_or_else(|| Backend::new(FilesystemBackend::new(self.repository_path.clone())))
+    }
+
+    /// Lock the repository for a read-modify-write cycle.
+    pub fn lock(&self) -> RepositoryResult<RepositoryLock>{
+        let timeout = VatConfig::init()
+            .map(|config| config.get_lock_timeout())
+            .map_err(|e| RepositoryError::ConfigError(e.to_string()))?;
+        self.backend().0.lock(timeout)
+    }
+
     pub fn get_package(&self, package_name: &str) -> Option<&PackageRegistry>{
         self.packages.get(package_name)
     }
@@ -126,53 +188,286 @@ impl Repository{
         }
 
         let git = Git::init(package.package_path.clone())?;
+        // archiving can take a while, the repository is only locked after
+        let archive_path = git.archive_tag(&package.package.version, &package.package_path)?;
+        let published = self.transaction(|repository| repository.publish_archive(&package, message, &archive_path));
 
-        let mut repo_package = RepoPackage::from_vat(package.clone());
-        let package_path = self.repository_path.join(repo_package.name.clone()).join(package.package.version.to_string());
-        if !package_path.exists(){
-            std::fs::create_dir_all(&package_path)?;
+        // clean up the source zip file
+        if let Err(e) = std::fs::remove_file(&archive_path){
+            Console::warn(&format!("Failed to remove {}: {}", archive_path.display(), e));
         }
+        published
+    }
 
-        repo_package.package_path = package_path;
+    /// Store the archive and add it to the index. The index is only touched
+    /// once the payload is in place, and the payload is removed again when
+    /// the index cannot be written.
+    fn publish_archive(&mut self, package: &Vat, message: &str, archive_path: &Path) -> RepositoryResult<()>{
+        if self.package_exists(&package.package.name, &package.package.version){
+            return Err(RepositoryError::PackageAlreadyExists(format!("Package {} version {} has been published", package.package.name, package.package.version)));
+        }
+        let backend = self.backend();
+
+        let mut repo_package = RepoPackage::from_vat(package.clone());
         repo_package.message = Some(message.to_string());
+        repo_package.published_at = Some(Utc::now());
+        repo_package.package_path = backend.0.store_payload(&package.package.name, &package.package.version, archive_path)?;
 
-        // link the package to the repoistiory
-        self.packages.entry(repo_package.name.clone())
-            .or_insert_with(|| PackageRegistry::new())
-            .link_package(package.package_path.clone());
+        let published = verify_payload(&repo_package)
+            .and_then(|_| {
+                repo_package.manifest = build_manifest(&repo_package.package_path)?;
+                // link the package to the repoistiory
+                self.packages.entry(repo_package.name.clone())
+                    .or_insert_with(|| PackageRegistry::new())
+                    .link_package(package.package_path.clone());
 
-        self.packages.entry(repo_package.name.clone())
-            .or_insert_with(|| PackageRegistry::new())
-            .add_package(repo_package);
+                self.packages.entry(repo_package.name.clone())
+                    .or_insert_with(|| PackageRegistry::new())
+                    .add_package(repo_package.clone());
+                self.save()
+            });
 
-        let repo_package_path = self.repository_path.join(package.package.name.clone());
-        git.zip_tag(package.package.version, package.package_path.clone(), repo_package_path)?;
-        self.save()?;
+        if let Err(e) = published{
+            if let Err(cleanup) = backend.0.remove_payload(&repo_package.name, Some(&repo_package.version)){
+                Console::warn(&format!("Failed to remove {}: {}", repo_package.package_path.display(), cleanup));
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
 
     pub fn link_package(&mut self, package: Vat) -> RepositoryResult<()>{
-        if self.packages.contains_key(&package.package.name){
-            return Err(RepositoryError::PackageAlreadyExists(format!("Package {} has already been linked", package.package.name)));
+        self.transaction(|repository| {
+            if repository.packages.contains_key(&package.package.name){
+                return Err(RepositoryError::PackageAlreadyExists(format!("Package {} has already been linked", package.package.name)));
+            }
+
+            repository.packages.entry(package.package.name.clone())
+                .or_insert_with(|| PackageRegistry::new())
+                .link_package(package.package_path.clone());
+
+            repository.save()?;
+            Ok(())
+        })
+    }
+
+    /// Remove a package with all its versions. Refuses while stacks, suites
+    /// or running processes use it, unless `force` is set, which is recorded
+    /// in the audit log.
+    pub fn remove_package(&mut self, package_name: &str, force: bool) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            let forced = repository.check_dependents(package_name, None, force)?;
+            repository.packages.remove(package_name);
+            repository.save()?;
+            // remove the folder from the repository
+            repository.discard_payload(package_name, None);
+            repository.record_forced(forced);
+            Ok(())
+        })
+    }
+
+    /// Remove a single published version, the package and its other
+    /// versions stay. Refuses like `remove_package`.
+    pub fn remove_version(&mut self, package_name: &str, version: &Version, force: bool) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            repository.published_version(package_name, version)?;
+            let forced = repository.check_dependents(package_name, Some(version), force)?;
+            if let Some(package_registry) = repository.packages.get_mut(package_name){
+                package_registry.versions.remove(version);
+                package_registry.channels.retain(|_, target| target != version);
+            }
+            repository.save()?;
+            repository.discard_payload(package_name, Some(version));
+            repository.record_forced(forced);
+            Ok(())
+        })
+    }
+
+    /// Remove the files of a version, or every version, the saved index no
+    /// longer lists. Files that cannot be removed are only left behind.
+    fn discard_payload(&self, package_name: &str, version: Option<&Version>){
+        if let Err(e) = self.backend().0.remove_payload(package_name, version){
+            let target = version.map(|version| format!("{}/{}", package_name, version)).unwrap_or(package_name.to_string());
+            Console::warn(&format!("Failed to remove the files of {}: {}", target, e));
         }
+    }
 
-        self.packages.entry(package.package.name.clone())
-            .or_insert_with(|| PackageRegistry::new())
-            .link_package(package.package_path.clone());
+    /// Refuses while something uses the target. Forced, returns the audit
+    /// entry to record once the removal went through.
+    fn check_dependents(&self, package_name: &str, version: Option<&Version>, force: bool) -> RepositoryResult<Option<AuditEntry>>{
+        let target = match version{
+            Some(version) => format!("{}/{}", package_name, version),
+            None => package_name.to_string(),
+        };
+        let dependents = find_dependents(self, package_name, version);
+        if dependents.is_empty(){
+            return Ok(None);
+        }
+        let listed = dependents.iter().map(|dependent| format!("  - {}", dependent)).collect::<Vec<_>>();
+        if !force{
+            return Err(RepositoryError::InUse(target, listed.join("\n")));
+        }
+        let detail = format!("forced while used by: {}", dependents.iter().map(|dependent| dependent.to_string()).collect::<Vec<_>>().join("; "));
+        Ok(Some(AuditEntry::new("remove", &target, &detail)))
+    }
 
-        self.save()?;
-        Ok(())
+    /// The removal is done at this point, a log that cannot be written only
+    /// warns.
+    fn record_forced(&self, forced: Option<AuditEntry>){
+        if let Some(entry) = forced{
+            if let Err(e) = self.backend().0.append_audit(&entry){
+                Console::warn(&format!("Failed to record the forced removal of {}: {}", entry.target, e));
+            }
+        }
     }
 
-    pub fn remove_package(&mut self, package_name: &str) -> RepositoryResult<()>{
-        self.packages.remove(package_name);
-        // remove the folder from the repository
-        let package_path = self.repository_path.join(package_name);
-        if package_path.exists(){
-            std::fs::remove_dir_all(package_path)?;
+    /// Append to the audit log after the change was saved. A failure only
+    /// warns, the change itself already happened.
+    fn record_audit(&self, entry: &AuditEntry){
+        if let Err(e) = self.backend().0.append_audit(entry){
+            Console::warn(&format!("Failed to record the {} of {} in the audit log: {}", entry.action, entry.target, e));
+        }
+    }
+
+    /// Changes recorded in the audit log, oldest first.
+    pub fn audit_log(&self) -> RepositoryResult<Vec<AuditEntry>>{
+        self.backend().0.read_audit()
+    }
+
+    /// Yank a version with `reason`, or restore it when `reason` is None.
+    pub fn yank(&mut self, package_name: &str, version: &Version, reason: Option<&str>) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            let repo_package = repository.published_version(package_name, version)?;
+            repo_package.yanked = reason.map(Notice::new);
+            repository.save()?;
+            Ok(())
+        })
+    }
+
+    /// Deprecate a version, or the whole package when `version` is None.
+    /// A None `reason` lifts the deprecation.
+    pub fn deprecate(&mut self, package_name: &str, version: Option<&Version>, reason: Option<&str>) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            let notice = reason.map(Notice::new);
+            match version{
+                Some(version) => repository.published_version(package_name, version)?.deprecated = notice,
+                None => {
+                    repository.packages.get_mut(package_name)
+                        .ok_or_else(|| RepositoryError::PackageNotFound(package_name.to_string()))?
+                        .deprecated = notice;
+                }
+            }
+            repository.save()?;
+            Ok(())
+        })
+    }
+
+    /// Point `channel` at a published version, or remove it when `version`
+    /// is None. Every move is recorded in the audit log.
+    pub fn set_channel(&mut self, package_name: &str, channel: &str, version: Option<&Version>) -> RepositoryResult<()>{
+        let channel = channel.trim_start_matches('@').to_lowercase();
+        let valid = !channel.is_empty()
+            && channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid{
+            return Err(RepositoryError::InvalidChannel(format!("`{}`, use letters, digits, - and _", channel)));
+        }
+        self.transaction(|repository| {
+            if let Some(version) = version{
+                repository.published_version(package_name, version)?;
+            }
+            let package_registry = repository.packages.get_mut(package_name)
+                .ok_or_else(|| RepositoryError::PackageNotFound(package_name.to_string()))?;
+            let previous = match version{
+                Some(version) => package_registry.channels.insert(channel.clone(), version.clone()),
+                None => package_registry.channels.remove(&channel),
+            };
+            if version.is_none() && previous.is_none(){
+                return Err(RepositoryError::InvalidChannel(format!("{} has no channel @{}", package_name, channel)));
+            }
+            repository.save()?;
+
+            let describe = |version: Option<&Version>| version.map(|version| version.to_string()).unwrap_or("none".to_string());
+            let detail = format!("{} -> {}", describe(previous.as_ref()), describe(version));
+            repository.record_audit(&AuditEntry::new("channel", &format!("{}/@{}", package_name, channel), &detail));
+            Ok(())
+        })
+    }
+
+    /// Whether `latest` of a package skips pre-release versions.
+    pub fn set_stable_latest(&mut self, package_name: &str, stable_latest: bool) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            repository.packages.get_mut(package_name)
+                .ok_or_else(|| RepositoryError::PackageNotFound(package_name.to_string()))?
+                .stable_latest = stable_latest;
+            repository.save()?;
+            Ok(())
+        })
+    }
+
+    /// Set the retention policy of a package, None falls back to the
+    /// configured one.
+    pub fn set_retention(&mut self, package_name: &str, retention: Option<Retention>) -> RepositoryResult<()>{
+        self.transaction(|repository| {
+            repository.packages.get_mut(package_name)
+                .ok_or_else(|| RepositoryError::PackageNotFound(package_name.to_string()))?
+                .retention = retention;
+            repository.save()?;
+            Ok(())
+        })
+    }
+
+    /// Remove the versions the retention policies let go, see `GcPlan`. The
+    /// index is saved before the payloads go, so it never lists a missing
+    /// version.
+    pub fn gc(&mut self, default: Option<&Retention>) -> RepositoryResult<GcPlan>{
+        self.transaction(|repository| {
+            let plan = GcPlan::build(repository, default)?;
+            if plan.is_empty(){
+                return Ok(plan);
+            }
+            for candidate in &plan.candidates{
+                if let Some(package_registry) = repository.packages.get_mut(&candidate.name){
+                    package_registry.versions.remove(&candidate.version);
+                }
+            }
+            repository.save()?;
+
+            for candidate in &plan.candidates{
+                repository.discard_payload(&candidate.name, Some(&candidate.version));
+                let target = format!("{}/{}", candidate.name, candidate.version);
+                repository.record_audit(&AuditEntry::new("gc", &target, &candidate.policy.to_string()));
+            }
+            Ok(plan)
+        })
+    }
+
+    fn published_version(&mut self, package_name: &str, version: &Version) -> RepositoryResult<&mut RepoPackage>{
+        self.packages.get_mut(package_name)
+            .and_then(|package_registry| package_registry.versions.get_mut(version))
+            .ok_or_else(|| RepositoryError::PackageNotFound(format!("{}/{} has not been published", package_name, version)))
+    }
+
+    /// Apply `change` to a fresh read of the repository while holding its
+    /// lock, so concurrent writers cannot lose each other's updates. `self`
+    /// only takes the result when the change succeeds.
+    fn transaction<T, F>(&mut self, change: F) -> RepositoryResult<T>
+    where F: FnOnce(&mut Repository) -> RepositoryResult<T>
+    {
+        self.check_writable()?;
+        let lock = self.lock()?;
+        let mut current = self.read()?;
+        current.lock_check = Some(lock.check());
+        let result = change(&mut current)?;
+        current.lock_check = None;
+        *self = current;
+        Ok(result)
+    }
+
+    fn check_writable(&self) -> RepositoryResult<()>{
+        if self.is_merged(){
+            return Err(RepositoryError::ReadOnly(format!("Combined view of {} cannot be saved, load a single repository", self.merged.join(", "))));
         }
-        self.save()?;
         Ok(())
     }
 
@@ -191,6 +486,8 @@ impl Repository{
         false
     }
 
+    /// Every configured repository, combined in priority order. With a single
+    /// repository this is that repository and can be saved.
     pub fn load() -> RepositoryResult<Repository>{
         let config_result = VatConfig::init();
         if config_result.is_err(){
@@ -198,8 +495,45 @@ impl Repository{
         }
         let config = config_result.unwrap();
 
+        let repositories = config.get_repositories();
+        if repositories.len() == 1{
+            return Repository::open(&repositories[0]);
+        }
+
+        let target = config.get_target_repository(None)
+            .ok_or_else(|| RepositoryError::ConfigError("No repository configured".to_string()))?;
+        let mut combined = Repository::new();
+        combined.repository_path = target.path;
+        combined.name = target.name;
+        for repository_config in &repositories{
+            match Repository::open(repository_config){
+                Ok(repository) => {
+                    combined.merge(repository);
+                    combined.merged.push(repository_config.name.clone());
+                }
+                Err(e) => {
+                    Console::warn(&format!("Skipping repository `{}`: {}", repository_config.name, e));
+                }
+            }
+        }
+        Ok(combined)
+    }
+
+    /// A single repository that can be written to, `name` or the default one.
+    pub fn load_named(name: Option<&str>) -> RepositoryResult<Repository>{
+        let config = VatConfig::init().map_err(|e| RepositoryError::ConfigError(e.to_string()))?;
+        let repository_config = config.get_target_repository(name)
+            .ok_or_else(|| RepositoryError::RepositoryNotFound(name.unwrap_or(DEFAULT_REPOSITORY).to_string()))?;
+        Repository::open(&repository_config)
+    }
+
+    pub fn open(repository_config: &RepositoryConfig) -> RepositoryResult<Repository>{
         let mut repository = Repository::new();
-        repository.repository_path = config.repository_path;
+        repository.repository_path = repository_config.path.clone();
+        repository.name = repository_config.name.clone();
+        if let Some(url) = &repository_config.url{
+            repository.backend = Some(Backend::new(HttpBackend::new(url.clone(), repository_config.path.clone())));
+        }
 
         // NOT SURE IF THIS IS THE BEST WAY TO HANDLE THIS
         // TODO: Find a better way to handle this
@@ -208,131 +542,324 @@ impl Repository{
         Ok(repository)
     }
 
+    /// Add the packages of a lower priority repository. The first repository
+    /// defining a package owns it: its channels, deprecation and
+    /// `stable_latest` apply and `latest` only picks from its versions.
+    /// Explicit versions are looked up one by one, the ones already present
+    /// shadow the ones of `other`, so pins into `other` still resolve.
+    pub fn merge(&mut self, other: Repository){
+        for (name, backend) in other.sources{
+            self.sources.entry(name).or_insert(backend);
+        }
+        for (package_name, other_registry) in other.packages{
+            let package_registry = match self.packages.get_mut(&package_name){
+                Some(package_registry) => package_registry,
+                None => {
+                    self.packages.insert(package_name, other_registry);
+                    continue;
+                }
+            };
+            if package_registry.main_brach_path.as_os_str().is_empty(){
+                package_registry.main_brach_path = other_registry.main_brach_path;
+            }
+            for (version, repo_package) in other_registry.versions{
+                package_registry.versions.entry(version).or_insert(repo_package);
+            }
+        }
+    }
+
+    pub fn is_merged(&self) -> bool{
+        !self.merged.is_empty()
+    }
+
 
     pub fn read(&self) -> RepositoryResult<Repository> {
-        let vat_repository_path = self.repository_path.join(VAT_REPOSITORY_FILE);
-        if !vat_repository_path.exists() {
-            return Err(RepositoryError::RepositoryNotFound("Cannot find vat_repository.toml".to_string()));
-        }
-        
-        let file = OpenOptions::new()
-            .read(true)
-            .open(&vat_repository_path)?;
-        
-        FileExt::lock_shared(&file)?;
-        
-        let toml_string = std::fs::read_to_string(vat_repository_path)?;
-        let mut repository: Repository = toml::from_str(&toml_string)?;
+        let backend = self.backend();
+        let mut repository = Repository::new();
+        repository.packages = backend.0.read_index()?;
         repository.repository_path = self.repository_path.clone();
+        repository.name = self.name.clone();
+        repository.backend = self.backend.clone();
+        repository.sources.insert(self.name.clone(), backend);
+        for package_registry in repository.packages.values_mut(){
+            package_registry.source = self.name.clone();
+            for repo_package in package_registry.versions.values_mut(){
+                repo_package.source = self.name.clone();
+            }
+        }
 
-        FileExt::unlock(&file)?;
-        
         Ok(repository)
     }
 
 
     pub fn save(&self) -> RepositoryResult<Self> {
-        let vat_repository_path = self.repository_path.join(VAT_REPOSITORY_FILE);
-        if !vat_repository_path.exists(){
-            // create the file
-            std::fs::File::create(&vat_repository_path)?;
-        }
-
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&vat_repository_path)?;
-        
-        FileExt::lock_exclusive(&file)?;
-        
-        let toml_string = toml::to_string(self)?;
-        file.write_all(toml_string.as_bytes())?;
-        
-        FileExt::unlock(&file)?;
-        
+        self.check_writable()?;
+        if self.lock_check.as_ref().is_some_and(|lock_check| !lock_check.is_held()){
+            return Err(RepositoryError::Locked(format!("another process took over the lock of {}, nothing was saved", self.backend().0.location())));
+        }
+        self.backend().0.write_index(&self.packages)?;
         Ok(self.clone())
     }
 
 
+    #[allow(clippy::too_many_arguments)]
     pub fn run(&self, package_name: &PackageName,
         command_name: &str,
         append_env: Option<Vec<PackageName>>,
-        detach: bool,
+        launcher: Launcher,
         add_env: Option<HashMap<String, String>>,
-        additonal_cmds: Option<Vec<String>>
+        additonal_cmds: Option<Vec<String>>,
+        limits: Option<Limits>
     ) -> RepositoryResult<()>{
-        let package_registry = self.get_package_by_package_name(package_name);
-        if package_registry.is_none(){
-            return Err(RepositoryError::PackageNotFound(format!("Package {} not found", package_name.name)));
+        let mut vat = self.load_run_package(package_name, append_env)?;
+        vat.set_limits(limits);
+        vat.run(command_name, launcher, add_env, additonal_cmds)?;
+        Ok(())
+    }
+
+
+    /// Same resolution as `run`, returning what would be executed instead.
+    #[allow(clippy::too_many_arguments)]
+    pub fn plan(&self, package_name: &PackageName,
+        command_name: &str,
+        append_env: Option<Vec<PackageName>>,
+        launcher: Launcher,
+        add_env: Option<HashMap<String, String>>,
+        additonal_cmds: Option<Vec<String>>,
+        limits: Option<Limits>
+    ) -> RepositoryResult<RunPlan>{
+        let mut vat = self.load_run_package(package_name, append_env.clone())?;
+        vat.set_limits(limits);
+        let mut plan = vat.plan(command_name, launcher, add_env, additonal_cmds)?;
+        if let Some(append_env) = append_env{
+            plan.packages.extend(self.resolve_packages(&append_env)?);
+        }
+        Ok(plan)
+    }
+
+
+    /// Local directory of the requested version, fetched from its repository
+    /// first when that one is remote.
+    pub fn fetch_package_path(&self, package_name: &PackageName) -> RepositoryResult<PathBuf>{
+        let not_found = || RepositoryError::PackageNotFound(format!("Package {} not found", package_name));
+        let package_registry = self.get_package_by_package_name(package_name).ok_or_else(not_found)?;
+        let version = match package_registry.resolve_version(package_name).ok_or_else(not_found)?{
+            PackageVersion::Version(version) => version,
+            _ => return package_registry.get_package_path(package_name).ok_or_else(not_found),
+        };
+        let repo_package = &package_registry.versions[&version];
+        let backend = self.sources.get(&repo_package.source).cloned().unwrap_or_else(|| self.backend());
+        backend.0.fetch_payload(repo_package)
+    }
+
+
+    /// Compare published versions against their manifests, every package
+    /// when `name` is None and every version when `version` is None.
+    pub fn verify(&self, name: Option<&str>, version: Option<&Version>) -> RepositoryResult<Vec<VerifyReport>>{
+        let mut repo_packages: Vec<&RepoPackage> = Vec::new();
+        for (package_name, package_registry) in &self.packages{
+            if name.is_some_and(|name| name != package_name){
+                continue;
+            }
+            repo_packages.extend(package_registry.versions.values()
+                .filter(|repo_package| version.is_none_or(|version| version == &repo_package.version)));
+        }
+        if repo_packages.is_empty(){
+            if let Some(name) = name{
+                let request = version.map(|version| format!("{}/{}", name, version)).unwrap_or(name.to_string());
+                return Err(RepositoryError::PackageNotFound(format!("No published version of {}", request)));
+            }
+        }
+        repo_packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+
+        let mut reports = Vec::new();
+        for repo_package in repo_packages{
+            reports.push(VerifyReport::check(repo_package)?);
+        }
+        Ok(reports)
+    }
+
+    /// Verify the published versions the requests resolve to, main branches
+    /// have nothing to verify.
+    pub fn verify_packages(&self, package_names: &[PackageName]) -> RepositoryResult<Vec<VerifyReport>>{
+        let mut reports = Vec::new();
+        for package_name in package_names{
+            let version = self.get_package_by_package_name(package_name)
+                .and_then(|package_registry| package_registry.resolve_version(package_name));
+            if let Some(PackageVersion::Version(version)) = version{
+                reports.extend(self.verify(Some(&package_name.name), Some(&version))?);
+            }
+        }
+        Ok(reports)
+    }
+
+
+    /// Yanked and deprecated notices of the version a request resolves to.
+    pub fn notices(&self, package_name: &PackageName) -> Vec<String>{
+        let mut notices = Vec::new();
+        let package_registry = match self.get_package_by_package_name(package_name){
+            Some(package_registry) => package_registry,
+            None => return notices,
+        };
+        if let Some(notice) = &package_registry.deprecated{
+            notices.push(format!("{} is deprecated: {}", package_name.name, notice));
+        }
+        if let Some(PackageVersion::Version(version)) = package_registry.resolve_version(package_name){
+            let repo_package = &package_registry.versions[&version];
+            if let Some(notice) = &repo_package.yanked{
+                notices.push(format!("{}/{} was yanked: {}", package_name.name, version, notice));
+            }
+            if let Some(notice) = &repo_package.deprecated{
+                notices.push(format!("{}/{} is deprecated: {}", package_name.name, version, notice));
+            }
         }
-        let package_registry = package_registry.unwrap();
-        let package_path = package_registry.get_package_path(package_name);
-        if package_path.is_none(){
-            return Err(RepositoryError::PackageNotFound(format!("Package {} not found", package_name.name)));
+        notices
+    }
+
+    fn warn_notices(&self, package_name: &PackageName){
+        for notice in self.notices(package_name){
+            Console::warn(&notice);
         }
-        let package_path = package_path.unwrap();
+    }
+
+
+    /// Read the package to run with the environment of the appended packages applied.
+    pub fn load_run_package(&self, package_name: &PackageName, append_env: Option<Vec<PackageName>>) -> RepositoryResult<Vat>{
+        let package_path = self.fetch_package_path(package_name)?;
+        self.warn_notices(package_name);
         let mut vat = Vat::read(package_path)?;
-        if append_env.is_some(){
-            vat.set_resolved_env(self.resolve_append_env(append_env.unwrap())?);
+        if let Some(append_env) = append_env{
+            vat.set_context(append_env.iter().map(|name| name.to_string()).collect());
+            vat.set_resolved_env(self.resolve_append_env(append_env)?);
         }
         dbg!(&vat.resolved_env);
         vat.resolve_env()?;
         dbg!(&vat.resolved_env);
-        vat.run(command_name, detach, add_env, additonal_cmds)?;
-        Ok(())
+        Ok(vat)
     }
 
 
-    pub fn resolve_stack_env(&self, stack: Stack) ->  HashMap<String, String>{
+    /// Concrete version and path of each requested package.
+    pub fn resolve_packages(&self, package_names: &[PackageName]) -> RepositoryResult<Vec<ResolvedPackage>>{
+        let issues = self.check_packages(package_names);
+        if !issues.is_empty(){
+            return Err(RepositoryError::ResolutionError(ResolutionIssues(issues)));
+        }
+
+        let mut packages = Vec::new();
+        for package_name in package_names{
+            let package_path = self.fetch_package_path(package_name)?;
+            let vat = Vat::read(package_path)?;
+            packages.push(ResolvedPackage::from_vat(&vat));
+        }
+        Ok(packages)
+    }
+
+
+    /// Resolve the environment of the stack's package and its active appended
+    /// packages. Fails before resolving anything when one of them is missing.
+    pub fn resolve_stack_env(&self, stack: Stack) -> RepositoryResult<HashMap<String, String>>{
         let mut package_names : Vec<PackageName> = Vec::new(); 
         // append main package
-        package_names.push(stack.package);
-        if stack.append.is_some(){
-            let append = stack.append.unwrap();
+        package_names.extend(stack.package.clone());
+        if let Some(append) = stack.active_append(){
             package_names.extend(append);
         }
+        self.resolve_append_env(package_names)
+    }
 
-        let output_env : HashMap<String, String> = HashMap::new();
-        let resolved_output = self.resolve_append_env(package_names);
-        if resolved_output.is_err(){
-            return output_env;
-        }else{
-            resolved_output.unwrap()
-        }
-        
 
+    /// Every problem that would stop the packages from resolving.
+    pub fn check_packages(&self, package_names: &[PackageName]) -> Vec<ResolutionIssue>{
+        let mut issues = Vec::new();
+        for package_name in package_names{
+            let package_registry = match self.get_package_by_package_name(package_name){
+                Some(package_registry) => package_registry,
+                None => {
+                    issues.push(ResolutionIssue::MissingPackage(package_name.clone()));
+                    continue;
+                }
+            };
+            let package_path = package_registry.get_package_path(package_name)
+                .filter(|package_path| !package_path.as_os_str().is_empty());
+            match package_path{
+                Some(_) => {
+                    let manifest = self.fetch_package_path(package_name)
+                        .and_then(|package_path| Vat::read(package_path).map_err(RepositoryError::from));
+                    if let Err(e) = manifest{
+                        issues.push(ResolutionIssue::UnreadableManifest(package_name.clone(), e.to_string()));
+                    }
+                }
+                None => {
+                    issues.push(ResolutionIssue::MissingVersion(package_name.clone()));
+                }
+            }
+        }
+        issues
     }
 
 
     pub fn resolve_append_env(&self, package_names: Vec<PackageName>) -> RepositoryResult<HashMap<String, String>>{
+        let issues = self.check_packages(&package_names);
+        if !issues.is_empty(){
+            return Err(RepositoryError::ResolutionError(ResolutionIssues(issues)));
+        }
+
         let mut resolved_env: HashMap<String, String> = HashMap::new();
         for package_name in package_names{
-            let package_registry = self.get_package_by_package_name(&package_name);
-            if package_registry.is_none(){
-                Console::error(&format!("Error: Package `{}/{}` not found in repository", package_name.name, package_name.version));
-            }else{
-                Console::info(&format!("Resolving package `{}/{}`", package_name.name, package_name.version));
-                let package_registry = package_registry.unwrap();
-                let package_path = package_registry.get_package_path(&package_name);
-                if package_path.is_some(){
-                    let mut vat = Vat::read(package_path.unwrap())?;
-                    vat.set_resolved_env(resolved_env.clone());
-                    vat.resolve_env()?;
-                    resolved_env.extend(vat.resolved_env);
-                }
-            }
-
+            Console::info(&format!("Resolving package `{}/{}`", package_name.name, package_name.version));
+            let package_path = self.fetch_package_path(&package_name)?;
+            self.warn_notices(&package_name);
+            let mut vat = Vat::read(package_path)?;
+            vat.set_resolved_env(resolved_env.clone());
+            vat.resolve_env()?;
+            resolved_env.extend(vat.resolved_env);
         }
         Ok(resolved_env)
     }
 }
 
 
+/// Why a version was yanked or a package deprecated, and by whom.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notice{
+    pub reason: String,
+    pub by: String,
+    pub at: DateTime<Utc>,
+}
+
+impl Notice{
+    pub fn new(reason: &str) -> Self{
+        Self{ reason: reason.to_string(), by: current_user(), at: Utc::now() }
+    }
+}
+
+impl std::fmt::Display for Notice{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}, {})", self.reason, self.by, self.at.with_timezone(&chrono::Local).format("%Y-%m-%d"))
+    }
+}
+
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PackageRegistry{
     pub main_brach_path: PathBuf,
     pub versions: HashMap<Version, RepoPackage>,
+    // applies to every version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<Notice>,
+    // overrides the configured retention for this package
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    // named pointers at published versions, requested as `pkg/@name`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, Version>,
+    // `latest` skips pre-release versions
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stable_latest: bool,
+    // repository owning the package in a combined view, see `merge`
+    #[serde(skip)]
+    pub source: String,
 }
 
 impl PackageRegistry{
@@ -340,6 +867,11 @@ impl PackageRegistry{
         Self{
             main_brach_path: PathBuf::new(),
             versions: HashMap::new(),
+            deprecated: None,
+            retention: None,
+            channels: BTreeMap::new(),
+            stable_latest: false,
+            source: String::new(),
         }
     }
 
@@ -351,26 +883,41 @@ impl PackageRegistry{
         self.main_brach_path = main_brach_path;
     }
 
+    /// Highest version that was not yanked, releases only with `stable_latest`.
+    pub fn latest_version(&self) -> Option<&Version>{
+        self.latest_version_except(None)
+    }
+
+    /// What `latest_version` would be once `excluded` is removed. Versions
+    /// merged in from lower priority repositories are not candidates.
+    pub fn latest_version_except(&self, excluded: Option<&Version>) -> Option<&Version>{
+        self.versions.values()
+            .filter(|repo_package| self.source.is_empty() || repo_package.source.is_empty() || repo_package.source == self.source)
+            .filter(|repo_package| excluded != Some(&repo_package.version))
+            .filter(|repo_package| repo_package.yanked.is_none())
+            .filter(|repo_package| !self.stable_latest || repo_package.version.pre.is_empty())
+            .map(|repo_package| &repo_package.version)
+            .max()
+    }
+
+    /// The concrete version `get_package_path` picks for a request, `Main` stays `Main`.
+    pub fn resolve_version(&self, package_name: &PackageName) -> Option<PackageVersion>{
+        let version = match &package_name.version{
+            PackageVersion::Main => return Some(PackageVersion::Main),
+            PackageVersion::Latest => self.latest_version()?,
+            PackageVersion::Channel(channel) => self.channels.get(channel)?,
+            PackageVersion::Version(version) => version,
+        };
+        if self.versions.contains_key(version){
+            return Some(PackageVersion::Version(version.clone()));
+        }
+        None
+    }
+
     pub fn get_package_path(&self, package_name: &PackageName) -> Option<PathBuf>{
-        match &package_name.version{
-            PackageVersion::Main => Some(self.main_brach_path.clone()),
-            PackageVersion::Latest => {
-                let version = self.versions.keys().max();
-                if version.is_none(){
-                    return None
-                }
-                let version = version.unwrap();
-                if self.versions.contains_key(&version){
-                    return Some(self.versions[&version].package_path.clone());
-                }
-                None
-            }
-            PackageVersion::Version(version) => {
-                if self.versions.contains_key(&version){
-                    return Some(self.versions[&version].package_path.clone());
-                }
-                None
-            }
+        match self.resolve_version(package_name)?{
+            PackageVersion::Version(version) => Some(self.versions[&version].package_path.clone()),
+            _ => Some(self.main_brach_path.clone()),
         }
     }
 
@@ -383,6 +930,19 @@ pub struct RepoPackage{
     pub package_path: PathBuf,
     pub repository: Option<Url>,
     pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime<Utc>>,
+    // hidden from `latest`, explicit pins still resolve
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yanked: Option<Notice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<Notice>,
+    // SHA-256 of every published file, by relative path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub manifest: BTreeMap<String, String>,
+    // name of the repository this version was read from
+    #[serde(skip)]
+    pub source: String,
 }
 
 impl RepoPackage{
@@ -394,8 +954,145 @@ impl RepoPackage{
             package_path: PathBuf::from(""),
             repository: vat.package.repository,
             message: None,
+            published_at: None,
+            yanked: None,
+            deprecated: None,
+            manifest: BTreeMap::new(),
+            source: String::new(),
         }
     }
 
 
-}
\ No newline at end of file
+}
+
+
+/// The stored payload is the version that was published.
+fn verify_payload(repo_package: &RepoPackage) -> RepositoryResult<()>{
+    let vat = Vat::read(repo_package.package_path.clone())
+        .map_err(|e| RepositoryError::PublishError(format!("Published files have no readable vat.toml: {}", e)))?;
+    if vat.package.name != repo_package.name || vat.package.version != repo_package.version{
+        return Err(RepositoryError::PublishError(format!("The tag contains {}/{}, expected {}/{}", vat.package.name, vat.package.version, repo_package.name, repo_package.version)));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn registry(versions: &[&str]) -> PackageRegistry{
+        let mut package_registry = PackageRegistry::new();
+        package_registry.link_package(PathBuf::from("main"));
+        for version in versions{
+            let version = Version::parse(version).unwrap();
+            package_registry.add_package(RepoPackage{
+                name: "pkg".to_string(),
+                version: version.clone(),
+                package_path: PathBuf::from(version.to_string()),
+                repository: None,
+                message: None,
+                published_at: None,
+                yanked: None,
+                deprecated: None,
+                manifest: BTreeMap::new(),
+                source: String::new(),
+            });
+        }
+        package_registry
+    }
+
+    fn resolved(package_registry: &PackageRegistry, package_name: &str) -> Option<String>{
+        package_registry.resolve_version(&PackageName::from_str(package_name)).map(|version| version.to_string())
+    }
+
+    #[test]
+    fn package_names_parse_versions_and_channels(){
+        let parse = |package_name: &str| PackageName::from_str(package_name).version;
+        assert_eq!(parse("pkg"), PackageVersion::Main);
+        assert_eq!(parse("pkg/main"), PackageVersion::Main);
+        assert_eq!(parse("pkg/latest"), PackageVersion::Latest);
+        assert_eq!(parse("pkg/1.2.3"), PackageVersion::Version(Version::parse("1.2.3").unwrap()));
+        assert_eq!(parse("pkg/@stable"), PackageVersion::Channel("stable".to_string()));
+        assert_eq!(parse("pkg/@Beta,"), PackageVersion::Channel("beta".to_string()));
+        assert_eq!(parse("pkg/not-a-version"), PackageVersion::Latest);
+
+        let package_name = PackageName::from_str("pkg/@stable");
+        assert_eq!(package_name.name, "pkg");
+        assert!(package_name.active);
+        assert_eq!(package_name.to_string(), "pkg/@stable");
+    }
+
+    fn sourced(name: &str, versions: &[&str], stable_latest: bool) -> Repository{
+        let mut package_registry = registry(versions);
+        package_registry.stable_latest = stable_latest;
+        package_registry.source = name.to_string();
+        for repo_package in package_registry.versions.values_mut(){
+            repo_package.source = name.to_string();
+        }
+        let mut repository = Repository::new();
+        repository.name = name.to_string();
+        repository.packages.insert("pkg".to_string(), package_registry);
+        repository
+    }
+
+    #[test]
+    fn merged_packages_follow_their_owning_repository(){
+        let mut combined = sourced("dev", &["1.0.0", "2.0.0-dev.1"], false);
+        combined.merge(sourced("studio", &["1.0.0", "1.5.0", "3.0.0"], true));
+        let package_registry = &combined.packages["pkg"];
+
+        // the owner's policy and versions decide `latest`
+        assert!(!package_registry.stable_latest);
+        assert_eq!(resolved(package_registry, "pkg/latest").as_deref(), Some("2.0.0-dev.1"));
+        // pins fall through to lower priority repositories
+        assert_eq!(resolved(package_registry, "pkg/3.0.0").as_deref(), Some("3.0.0"));
+        assert_eq!(package_registry.versions[&Version::parse("1.0.0").unwrap()].source, "dev");
+
+        let mut combined = sourced("studio", &["1.5.0"], true);
+        combined.merge(sourced("dev", &["2.0.0-dev.1"], false));
+        assert!(combined.packages["pkg"].stable_latest);
+        assert_eq!(resolved(&combined.packages["pkg"], "pkg/latest").as_deref(), Some("1.5.0"));
+    }
+
+    #[test]
+    fn latest_skips_yanked_versions(){
+        let mut package_registry = registry(&["1.0.0", "1.1.0", "1.2.0"]);
+        package_registry.versions.get_mut(&Version::parse("1.2.0").unwrap()).unwrap().yanked = Some(Notice::new("broken"));
+
+        assert_eq!(resolved(&package_registry, "pkg/latest").as_deref(), Some("1.1.0"));
+        // an explicit pin still resolves
+        assert_eq!(resolved(&package_registry, "pkg/1.2.0").as_deref(), Some("1.2.0"));
+        assert_eq!(resolved(&package_registry, "pkg/9.9.9"), None);
+        assert_eq!(resolved(&package_registry, "pkg").as_deref(), Some("main"));
+        assert_eq!(package_registry.latest_version_except(Some(&Version::parse("1.1.0").unwrap())).map(|version| version.to_string()).as_deref(), Some("1.0.0"));
+
+        for version in package_registry.versions.values_mut(){
+            version.yanked = Some(Notice::new("all broken"));
+        }
+        assert_eq!(resolved(&package_registry, "pkg/latest"), None);
+    }
+
+    #[test]
+    fn stable_latest_skips_pre_releases(){
+        let mut package_registry = registry(&["1.0.0", "2.0.0-rc.1"]);
+        assert_eq!(resolved(&package_registry, "pkg/latest").as_deref(), Some("2.0.0-rc.1"));
+
+        package_registry.stable_latest = true;
+        assert_eq!(resolved(&package_registry, "pkg/latest").as_deref(), Some("1.0.0"));
+        assert_eq!(resolved(&package_registry, "pkg/2.0.0-rc.1").as_deref(), Some("2.0.0-rc.1"));
+    }
+
+    #[test]
+    fn channels_resolve_to_their_version(){
+        let mut package_registry = registry(&["1.0.0", "2.0.0-rc.1"]);
+        package_registry.channels.insert("beta".to_string(), Version::parse("2.0.0-rc.1").unwrap());
+        // a channel whose version was removed
+        package_registry.channels.insert("old".to_string(), Version::parse("0.9.0").unwrap());
+
+        assert_eq!(resolved(&package_registry, "pkg/@beta").as_deref(), Some("2.0.0-rc.1"));
+        assert_eq!(resolved(&package_registry, "pkg/@old"), None);
+        assert_eq!(resolved(&package_registry, "pkg/@missing"), None);
+        assert_eq!(package_registry.get_package_path(&PackageName::from_str("pkg/@beta")), Some(PathBuf::from("2.0.0-rc.1")));
+    }
+}
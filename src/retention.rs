@@ -0,0 +1,218 @@
+use std::fs;
+use std::path::PathBuf;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::dependents::{collect_dependents, Dependent};
+use crate::repository::{RepoPackage, Repository};
+use crate::store::{dir_size, reclaimable, ObjectStore};
+
+
+/// Which published versions `vat repo gc` keeps. A version is kept when any
+/// rule keeps it, a policy without rules keeps everything. The latest
+/// version, versions still in use and the main branch are always kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention{
+    // the highest versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    // versions published within that many days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_days: Option<u64>,
+    // versions published on or after that date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_since: Option<DateTime<Utc>>,
+}
+
+impl Retention{
+    pub fn is_empty(&self) -> bool{
+        self.keep_last.is_none() && self.keep_days.is_none() && self.keep_since.is_none()
+    }
+
+    fn keeps(&self, index: usize, published_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool{
+        if self.is_empty(){
+            return true;
+        }
+        if self.keep_last.is_some_and(|keep_last| index < keep_last){
+            return true;
+        }
+        // without a known date a version is never old enough
+        if self.keep_days.is_some_and(|days| {
+            published_at.is_none_or(|at| now.signed_duration_since(at).num_days() < days as i64)
+        }){
+            return true;
+        }
+        self.keep_since.is_some_and(|since| published_at.is_none_or(|at| at >= since))
+    }
+}
+
+/// Parse a `--keep-since` date, either `YYYY-MM-DD` (midnight UTC) or RFC 3339.
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>, String>{
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d"){
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|at| at.with_timezone(&Utc))
+        .map_err(|_| format!("`{}` is neither YYYY-MM-DD nor an RFC 3339 date", value))
+}
+
+impl std::fmt::Display for Retention{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut rules = Vec::new();
+        if let Some(keep_last) = self.keep_last{
+            rules.push(format!("keep the last {}", keep_last));
+        }
+        if let Some(keep_days) = self.keep_days{
+            rules.push(format!("keep {} days", keep_days));
+        }
+        if let Some(keep_since) = self.keep_since{
+            rules.push(format!("keep since {}", keep_since.format("%Y-%m-%d %H:%M:%S UTC")));
+        }
+        if rules.is_empty(){
+            return write!(f, "keep everything");
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+
+/// A published version the retention policy lets go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcCandidate{
+    pub name: String,
+    pub version: Version,
+    pub package_path: PathBuf,
+    pub published_at: Option<DateTime<Utc>>,
+    // size of its files, shared ones included
+    pub size: u64,
+    pub policy: Retention,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcPlan{
+    pub candidates: Vec<GcCandidate>,
+    // references that saved a version the policy would remove
+    pub in_use: Vec<(String, Version, Dependent)>,
+    // bytes actually freed on disk, hardlinked files count once they are
+    // no longer linked from a kept version
+    pub freed: u64,
+}
+
+impl GcPlan{
+    /// Versions to remove under each package's policy, or `default` for the
+    /// packages without one.
+    pub fn build(repository: &Repository, default: Option<&Retention>) -> std::io::Result<GcPlan>{
+        let dependents = collect_dependents(repository);
+        let now = Utc::now();
+        let mut plan = GcPlan{ candidates: Vec::new(), in_use: Vec::new(), freed: 0 };
+
+        let mut names: Vec<&String> = repository.packages.keys().collect();
+        names.sort();
+        for name in names{
+            let package_registry = &repository.packages[name];
+            let policy = match package_registry.retention.as_ref().or(default){
+                Some(policy) => policy,
+                None => continue,
+            };
+            let latest = package_registry.latest_version();
+            let mut repo_packages: Vec<&RepoPackage> = package_registry.versions.values().collect();
+            repo_packages.sort_by(|a, b| b.version.cmp(&a.version));
+
+            for (index, repo_package) in repo_packages.into_iter().enumerate(){
+                let published_at = published_at(repo_package);
+                if Some(&repo_package.version) == latest || policy.keeps(index, published_at, now){
+                    continue;
+                }
+                let users: Vec<&Dependent> = dependents.iter()
+                    .filter(|dependent| dependent.uses(repository, name, Some(&repo_package.version)))
+                    .collect();
+                if !users.is_empty(){
+                    plan.in_use.extend(users.into_iter().map(|dependent| (name.clone(), repo_package.version.clone(), dependent.clone())));
+                    continue;
+                }
+                let size = if repo_package.package_path.exists(){ dir_size(&repo_package.package_path)? }else{ 0 };
+                plan.candidates.push(GcCandidate{
+                    name: name.clone(),
+                    version: repo_package.version.clone(),
+                    package_path: repo_package.package_path.clone(),
+                    published_at,
+                    size,
+                    policy: policy.clone(),
+                });
+            }
+        }
+
+        let dirs: Vec<PathBuf> = plan.candidates.iter().map(|candidate| candidate.package_path.clone()).collect();
+        plan.freed = reclaimable(&dirs, ObjectStore::open(&repository.repository_path).as_ref())?;
+        Ok(plan)
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.candidates.is_empty()
+    }
+}
+
+/// Versions published before the date was recorded fall back to the time
+/// their directory was written.
+fn published_at(repo_package: &RepoPackage) -> Option<DateTime<Utc>>{
+    repo_package.published_at.or_else(|| {
+        fs::metadata(&repo_package.package_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(DateTime::<Utc>::from)
+    })
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn empty_policy_keeps_everything(){
+        let now = Utc::now();
+        let policy = Retention::default();
+        assert!(policy.is_empty());
+        assert!(policy.keeps(100, Some(now - Duration::days(1000)), now));
+        assert_eq!(policy.to_string(), "keep everything");
+    }
+
+    #[test]
+    fn any_rule_keeps_a_version(){
+        let now = Utc::now();
+        let old = Some(now - Duration::days(40));
+        let recent = Some(now - Duration::days(3));
+
+        let keep_last = Retention{ keep_last: Some(2), ..Retention::default() };
+        assert!(keep_last.keeps(0, old, now));
+        assert!(keep_last.keeps(1, old, now));
+        assert!(!keep_last.keeps(2, recent, now));
+
+        let keep_days = Retention{ keep_days: Some(7), ..Retention::default() };
+        assert!(keep_days.keeps(5, recent, now));
+        assert!(!keep_days.keeps(5, old, now));
+        assert!(keep_days.keeps(5, None, now));
+
+        let keep_since = Retention{ keep_since: Some(now - Duration::days(10)), ..Retention::default() };
+        assert!(keep_since.keeps(5, recent, now));
+        assert!(keep_since.keeps(5, Some(now - Duration::days(10)), now));
+        assert!(!keep_since.keeps(5, old, now));
+        assert!(keep_since.keeps(5, None, now));
+
+        let combined = Retention{ keep_last: Some(1), keep_days: Some(7), keep_since: None };
+        assert!(combined.keeps(0, old, now));
+        assert!(combined.keeps(3, recent, now));
+        assert!(!combined.keeps(3, old, now));
+        assert_eq!(combined.to_string(), "keep the last 1, keep 7 days");
+    }
+
+    #[test]
+    fn since_dates_parse(){
+        assert_eq!(parse_since("2024-03-01").unwrap().to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        assert_eq!(parse_since("2024-03-01T12:00:00+02:00").unwrap().to_rfc3339(), "2024-03-01T10:00:00+00:00");
+        assert!(parse_since("yesterday").is_err());
+        assert!(parse_since("2024-13-01").is_err());
+    }
+}
@@ -1,36 +1,462 @@
-use crate::repository::{PackageName, Repository};
-use crate::errors::{StackError, StackResult};
+use crate::repository::{PackageName, PackageVersion, Repository};
+use crate::errors::{RepositoryError, StackError, StackResult};
+use crate::console::Console;
+use crate::plan::{Launcher, RunPlan};
+use crate::config::VatConfig;
+use crate::atomic::write_atomic;
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use fs2::FileExt;
 use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
 
+pub const STACKS_FILE: &str = "stacks.toml";
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Stacks{
-    pub stacks: Vec<Stack>
+    pub stacks: Vec<Stack>,
+    #[serde(skip)]
+    pub stacks_path: PathBuf,
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Stack{
     pub name: String,
+    // empty when inherited from `extends`
+    #[serde(default)]
     pub command: String,
-    pub package: PackageName,
+    // inherited from `extends` when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<PackageName>,
     pub append: Option<Vec<PackageName>>,
-    pub detach: bool,
+    // inherited from `extends` when not set, off for a stack without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detach: Option<bool>,
     pub icon: Option<String>,
     pub modified_at: Option<DateTime<Utc>>,
     pub created_at: Option<DateTime<Utc>>,
+    // stack this one builds on
+    pub extends: Option<String>,
+    // package names dropped from the inherited append list
+    pub remove: Option<Vec<String>>,
+    // set while the stack is pinned to concrete versions
+    pub frozen_at: Option<DateTime<Utc>>,
+    // the floating definition `thaw` restores
+    pub floating: Option<Box<Stack>>,
+}
+
+
+impl Stacks{
+    /// Stacks shared through the repository, stored next to `vat_repository.toml`.
+    pub fn load() -> StackResult<Stacks>{
+        let config = VatConfig::init().map_err(|e| StackError::ConfigError(e.to_string()))?;
+        Stacks::open(config.repository_path.join(STACKS_FILE))
+    }
+
+    /// Stacks private to the current user, stored in the vat app directory.
+    pub fn load_user() -> StackResult<Stacks>{
+        let app_dir = VatConfig::get_app_dir()
+            .ok_or_else(|| StackError::ConfigError("Failed to get app directory".to_string()))?;
+        Stacks::open(app_dir.join(STACKS_FILE))
+    }
+
+    pub fn open(stacks_path: PathBuf) -> StackResult<Stacks>{
+        let mut stacks = Stacks{ stacks: Vec::new(), stacks_path };
+        stacks.read()?;
+        Ok(stacks)
+    }
+
+    pub fn read(&mut self) -> StackResult<()>{
+        if !self.stacks_path.exists(){
+            self.stacks = Vec::new();
+            return Ok(());
+        }
+
+        // written atomically, readers never see a partial file
+        let toml_string = std::fs::read_to_string(&self.stacks_path)?;
+
+        let stacks: Stacks = toml::from_str(&toml_string)?;
+        self.stacks = stacks.stacks;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Stack>{
+        self.stacks.iter().find(|stack| stack.name == name)
+    }
+
+    pub fn list(&self) -> &Vec<Stack>{
+        &self.stacks
+    }
+
+    /// The stack with everything it inherits applied, ready to run.
+    pub fn flatten(&self, name: &str) -> StackResult<Stack>{
+        flatten_stack(&self.stacks, name)
+    }
+
+    pub fn create(&mut self, mut stack: Stack) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            if stacks.iter().any(|existing| existing.name == stack.name){
+                return Err(StackError::StackAlreadyExists(stack.name.clone()));
+            }
+            let now = Utc::now();
+            stack.created_at = Some(now);
+            stack.modified_at = Some(now);
+            stacks.push(stack.clone());
+            flatten_stack(stacks, &stack.name)?;
+            Ok(stack)
+        })
+    }
+
+    /// Replace the stack with the same name, keeping its creation time.
+    pub fn update(&mut self, mut stack: Stack) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            let existing = stacks.iter_mut()
+                .find(|existing| existing.name == stack.name)
+                .ok_or_else(|| StackError::StackNotFound(stack.name.clone()))?;
+            stack.created_at = existing.created_at;
+            stack.modified_at = Some(Utc::now());
+            *existing = stack.clone();
+            flatten_stack(stacks, &stack.name)?;
+            Ok(stack)
+        })
+    }
+
+    pub fn rename(&mut self, name: &str, new_name: &str) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            if stacks.iter().any(|existing| existing.name == new_name){
+                return Err(StackError::StackAlreadyExists(new_name.to_string()));
+            }
+            let existing = stacks.iter_mut()
+                .find(|existing| existing.name == name)
+                .ok_or_else(|| StackError::StackNotFound(name.to_string()))?;
+            existing.name = new_name.to_string();
+            existing.modified_at = Some(Utc::now());
+            let renamed = existing.clone();
+
+            for child in stacks.iter_mut().filter(|child| child.extends.as_deref() == Some(name)){
+                child.extends = Some(new_name.to_string());
+            }
+            Ok(renamed)
+        })
+    }
+
+    pub fn delete(&mut self, name: &str) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            let index = stacks.iter()
+                .position(|existing| existing.name == name)
+                .ok_or_else(|| StackError::StackNotFound(name.to_string()))?;
+            let children: Vec<String> = stacks.iter()
+                .filter(|child| child.extends.as_deref() == Some(name))
+                .map(|child| child.name.clone())
+                .collect();
+            if !children.is_empty(){
+                return Err(StackError::StackInUse(format!("{} is extended by {}", name, children.join(", "))));
+            }
+            Ok(stacks.remove(index))
+        })
+    }
+
+    /// Pin every package of the flattened stack to the version the repository
+    /// currently resolves it to. The floating definition is kept for `thaw`.
+    pub fn freeze(&mut self, name: &str, repository: &Repository) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            let index = stacks.iter()
+                .position(|existing| existing.name == name)
+                .ok_or_else(|| StackError::StackNotFound(name.to_string()))?;
+
+            // re-freezing starts again from the floating requests
+            let floating = match stacks[index].floating.take(){
+                Some(floating) => *floating,
+                None => stacks[index].clone(),
+            };
+            stacks[index] = floating.clone();
+            let flat = flatten_stack(stacks, name)?;
+
+            let mut frozen = flat.clone();
+            frozen.package = Some(freeze_package(flat.main_package()?, repository)?);
+            // switched off packages are pinned when they resolve and kept as
+            // requested otherwise, like an export does
+            frozen.append = match &flat.append{
+                Some(append) => Some(append.iter()
+                    .map(|package| match freeze_package(package, repository){
+                        Err(_) if !package.active => Ok(package.clone()),
+                        pinned => pinned,
+                    })
+                    .collect::<StackResult<Vec<_>>>()?),
+                None => None,
+            };
+            let now = Utc::now();
+            frozen.created_at = floating.created_at;
+            frozen.modified_at = Some(now);
+            frozen.frozen_at = Some(now);
+            frozen.floating = Some(Box::new(floating));
+
+            stacks[index] = frozen.clone();
+            Ok(frozen)
+        })
+    }
+
+    pub fn thaw(&mut self, name: &str) -> StackResult<Stack>{
+        self.transaction(|stacks| {
+            let existing = stacks.iter_mut()
+                .find(|existing| existing.name == name)
+                .ok_or_else(|| StackError::StackNotFound(name.to_string()))?;
+            let mut floating = *existing.floating.take()
+                .ok_or_else(|| StackError::InvalidStack(format!("{} is not frozen", name)))?;
+            floating.name = existing.name.clone();
+            floating.created_at = existing.created_at;
+            floating.modified_at = Some(Utc::now());
+            floating.frozen_at = None;
+            *existing = floating.clone();
+            Ok(floating)
+        })
+    }
+
+    /// Switch one appended package of a stack on or off without removing it.
+    pub fn toggle(&mut self, name: &str, package_name: &str, active: Option<bool>) -> StackResult<bool>{
+        self.transaction(|stacks| {
+            let index = stacks.iter()
+                .position(|existing| existing.name == name)
+                .ok_or_else(|| StackError::StackNotFound(name.to_string()))?;
+            let inherited = find_appended(&flatten_stack(stacks, name)?, package_name);
+            // the floating definition inherits the floating request, not the pin
+            let floating_inherited = match &stacks[index].floating{
+                Some(floating) => {
+                    let mut floating_stacks = stacks.clone();
+                    floating_stacks[index] = (**floating).clone();
+                    find_appended(&flatten_stack(&floating_stacks, name)?, package_name)
+                }
+                None => None,
+            };
+
+            let existing = &mut stacks[index];
+            let active = existing.set_package_active(package_name, active, inherited.as_ref())?;
+            // keep the choice when the stack is thawed
+            if let Some(floating) = existing.floating.as_mut(){
+                let _ = floating.set_package_active(package_name, Some(active), floating_inherited.as_ref());
+            }
+            existing.modified_at = Some(Utc::now());
+            Ok(active)
+        })
+    }
+
+    /// Check every stack against the repository, returning the problems of
+    /// the ones that would not resolve.
+    pub fn check(&self, repository: &Repository) -> Vec<(String, Vec<String>)>{
+        let mut failures = Vec::new();
+        for stack in &self.stacks{
+            let problems = match flatten_stack(&self.stacks, &stack.name){
+                Ok(flat) => {
+                    let mut package_names: Vec<PackageName> = flat.package.iter().cloned().collect();
+                    package_names.extend(flat.active_append().unwrap_or_default());
+                    repository.check_packages(&package_names).iter().map(|issue| issue.to_string()).collect()
+                }
+                Err(e) => vec![e.to_string()],
+            };
+            if !problems.is_empty(){
+                failures.push((stack.name.clone(), problems));
+            }
+        }
+        failures
+    }
+
+    /// Re-read the file, apply `change` and write it back while holding an
+    /// exclusive lock on `stacks.toml.lock`, so concurrent edits are not
+    /// lost. The file is replaced atomically, a failed write leaves the old
+    /// stacks in place.
+    fn transaction<T, F>(&mut self, change: F) -> StackResult<T>
+    where F: FnOnce(&mut Vec<Stack>) -> StackResult<T>
+    {
+        if let Some(parent) = self.stacks_path.parent(){
+            std::fs::create_dir_all(parent)?;
+        }
+        let lock_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(&self.stacks_path))?;
+        FileExt::lock_exclusive(&lock_file)?;
+
+        let mut current = Stacks{ stacks: Vec::new(), stacks_path: self.stacks_path.clone() };
+        current.read()?;
+        let mut stacks = current.stacks;
+
+        let result = change(&mut stacks)?;
+        let toml_string = toml::to_string(&Stacks{ stacks: stacks.clone(), stacks_path: PathBuf::new() })?;
+        write_atomic(&self.stacks_path, toml_string.as_bytes(), true)?;
+
+        FileExt::unlock(&lock_file)?;
+        self.stacks = stacks;
+        Ok(result)
+    }
+}
+
+fn lock_path(stacks_path: &Path) -> PathBuf{
+    let mut file_name = stacks_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".lock");
+    stacks_path.with_file_name(file_name)
+}
+
+
+/// Resolve `extends` chains. The child's package and detach flag win when it
+/// sets them, an empty command is inherited, and appended packages replace
+/// inherited ones of the same name.
+pub fn flatten_stack(stacks: &[Stack], name: &str) -> StackResult<Stack>{
+    let mut chain: Vec<&Stack> = Vec::new();
+    let mut current = Some(name.to_string());
+    while let Some(stack_name) = current{
+        if chain.iter().any(|stack| stack.name == stack_name){
+            let mut names: Vec<String> = chain.iter().map(|stack| stack.name.clone()).collect();
+            names.push(stack_name);
+            return Err(StackError::StackCycle(names.join(" -> ")));
+        }
+        let stack = stacks.iter()
+            .find(|stack| stack.name == stack_name)
+            .ok_or_else(|| StackError::StackNotFound(stack_name.clone()))?;
+        chain.push(stack);
+        current = stack.extends.clone();
+    }
+
+    // apply from the root down to the requested stack
+    let mut chain = chain.into_iter().rev();
+    let mut flat = chain.next().unwrap().clone();
+    for child in chain{
+        let mut append = flat.append.take().unwrap_or_default();
+        if let Some(remove) = &child.remove{
+            append.retain(|package| !remove.contains(&package.name));
+        }
+        for package in child.append.iter().flatten(){
+            match append.iter_mut().find(|existing| existing.name == package.name){
+                Some(existing) => *existing = package.clone(),
+                None => append.push(package.clone()),
+            }
+        }
+
+        flat.append = if append.is_empty(){ None }else{ Some(append) };
+        if !child.command.is_empty(){
+            flat.command = child.command.clone();
+        }
+        if child.package.is_some(){
+            flat.package = child.package.clone();
+        }
+        if child.detach.is_some(){
+            flat.detach = child.detach;
+        }
+        flat.name = child.name.clone();
+        flat.icon = child.icon.clone().or(flat.icon);
+        flat.created_at = child.created_at;
+        flat.modified_at = child.modified_at;
+    }
+    flat.extends = None;
+    flat.remove = None;
+    flat.detach = Some(flat.is_detached());
+
+    if flat.package.is_none(){
+        return Err(StackError::Command(format!("Stack {} has no package", name)));
+    }
+    if flat.command.is_empty(){
+        return Err(StackError::Command(format!("Stack {} has no command", name)));
+    }
+    Ok(flat)
+}
+
+
+fn find_appended(stack: &Stack, package_name: &str) -> Option<PackageName>{
+    stack.append.iter()
+        .flatten()
+        .find(|package| package.name == package_name)
+        .cloned()
+}
+
+fn freeze_package(package_name: &PackageName, repository: &Repository) -> StackResult<PackageName>{
+    let version = repository.get_package_by_package_name(package_name)
+        .and_then(|package_registry| package_registry.resolve_version(package_name))
+        .ok_or_else(|| RepositoryError::PackageNotFound(package_name.to_string()))?;
+    if version == PackageVersion::Main{
+        Console::warn(&format!("`{}` stays on the main branch, it has no published version to pin", package_name.name));
+    }
+    Ok(PackageName{ name: package_name.name.clone(), version, active: package_name.active })
 }
 
 
 impl Stack{
-    pub fn run(self, add_env: Option<HashMap<String, String>>, additonal_cmds: Option<Vec<String>>) -> StackResult<()>{
+    pub fn is_frozen(&self) -> bool{
+        self.floating.is_some()
+    }
+
+    /// The package to run, always set once the stack is flattened.
+    pub fn main_package(&self) -> StackResult<&PackageName>{
+        self.package.as_ref()
+            .ok_or_else(|| StackError::Command(format!("Stack {} has no package", self.name)))
+    }
+
+    pub fn is_detached(&self) -> bool{
+        self.detach.unwrap_or(false)
+    }
+
+    /// Appended packages that are switched on, the ones run and resolved.
+    pub fn active_append(&self) -> Option<Vec<PackageName>>{
+        let append: Vec<PackageName> = self.append.iter()
+            .flatten()
+            .filter(|package| package.active)
+            .cloned()
+            .collect();
+        if append.is_empty(){ None }else{ Some(append) }
+    }
+
+    /// Switch an appended package on or off, flipping it when `active` is
+    /// None. Inherited packages get an override entry in this stack.
+    fn set_package_active(&mut self, package_name: &str, active: Option<bool>, inherited: Option<&PackageName>) -> StackResult<bool>{
+        let append = self.append.get_or_insert_with(Vec::new);
+        let package = match append.iter_mut().find(|package| package.name == package_name){
+            Some(package) => package,
+            None => {
+                let inherited = inherited
+                    .ok_or_else(|| StackError::InvalidStack(format!("{} does not append {}", self.name, package_name)))?;
+                append.push(inherited.clone());
+                append.last_mut().unwrap()
+            }
+        };
+        package.active = active.unwrap_or(!package.active);
+        Ok(package.active)
+    }
+
+    pub fn new(name: &str, package: Option<PackageName>, command: &str) -> Self{
+        Self{
+            name: name.to_string(),
+            command: command.to_string(),
+            package,
+            append: None,
+            detach: None,
+            icon: None,
+            modified_at: None,
+            created_at: None,
+            extends: None,
+            remove: None,
+            frozen_at: None,
+            floating: None,
+        }
+    }
+
+    /// How the stack is launched, `terminal` opens it in a new window whatever
+    /// the stack's detach flag says.
+    pub fn launcher(&self, terminal: bool) -> Launcher{
+        Launcher::new(self.is_detached(), terminal)
+    }
+
+    pub fn run(self, terminal: bool, add_env: Option<HashMap<String, String>>, additonal_cmds: Option<Vec<String>>) -> StackResult<()>{
         let repository = Repository::load()?;
         let run_result = repository.run(
-                                                        &self.package,
+                                                        self.main_package()?,
                                                         &self.command,
-                                                        self.append,
-                                                        true,
+                                                        self.active_append(),
+                                                        self.launcher(terminal),
                                                         add_env,
-                                                        additonal_cmds
+                                                        additonal_cmds,
+                                                        None
                                                     );
         match run_result{
             Ok(_) => {
@@ -42,10 +468,178 @@ impl Stack{
         }
     }
 
+    /// Resolve the stack like `run` does and return the plan, nothing is spawned.
+    pub fn dry_run(self, terminal: bool, add_env: Option<HashMap<String, String>>, additonal_cmds: Option<Vec<String>>) -> StackResult<RunPlan>{
+        let repository = Repository::load()?;
+        let plan = repository.plan(
+                                    self.main_package()?,
+                                    &self.command,
+                                    self.active_append(),
+                                    self.launcher(terminal),
+                                    add_env,
+                                    additonal_cmds,
+                                    None
+                                )?;
+        Ok(plan)
+    }
+
     pub fn env_from_stack(self) -> StackResult<HashMap<String, String>>{
         let repository = Repository::load()?;
-        let result_env = repository.resolve_stack_env(self);
+        let result_env = repository.resolve_stack_env(self)?;
         Ok(result_env)
     }
 
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::repository::{PackageRegistry, RepoPackage};
+    use std::fs;
+
+    fn stack(name: &str, extends: Option<&str>, command: &str, append: &[&str]) -> Stack{
+        let mut stack = Stack::new(name, Some(PackageName::from_str(&format!("{}-app/latest", name))), command);
+        stack.extends = extends.map(str::to_string);
+        if !append.is_empty(){
+            stack.append = Some(append.iter().map(|package| PackageName::from_str(package)).collect());
+        }
+        stack
+    }
+
+    #[test]
+    fn child_overrides_inherited_packages(){
+        let mut child = stack("child", Some("base"), "", &["tools/2.0.0", "extra"]);
+        child.detach = Some(true);
+        let stacks = vec![
+            stack("base", None, "run-base", &["tools/1.0.0", "libs/latest"]),
+            child,
+        ];
+        let flat = flatten_stack(&stacks, "child").unwrap();
+
+        assert_eq!(flat.name, "child");
+        assert_eq!(flat.command, "run-base");
+        assert_eq!(flat.package, Some(PackageName::from_str("child-app/latest")));
+        assert_eq!(flat.detach, Some(true));
+        assert_eq!(flat.extends, None);
+        assert_eq!(flat.append, Some(vec![
+            PackageName::from_str("tools/2.0.0"),
+            PackageName::from_str("libs/latest"),
+            PackageName::from_str("extra"),
+        ]));
+    }
+
+    #[test]
+    fn child_inherits_package_and_detach(){
+        let mut base = stack("base", None, "run-base", &[]);
+        base.detach = Some(true);
+        let mut child = stack("child", Some("base"), "", &["extra"]);
+        child.package = None;
+        let stacks = vec![base.clone(), child.clone()];
+        let flat = flatten_stack(&stacks, "child").unwrap();
+        assert_eq!(flat.package, Some(PackageName::from_str("base-app/latest")));
+        assert_eq!(flat.detach, Some(true));
+
+        // later changes to the base reach the child
+        base.package = Some(PackageName::from_str("other-app/1.0.0"));
+        base.detach = None;
+        let flat = flatten_stack(&[base.clone(), child.clone()], "child").unwrap();
+        assert_eq!(flat.package, Some(PackageName::from_str("other-app/1.0.0")));
+        assert_eq!(flat.detach, Some(false));
+
+        // a child can still switch detaching off explicitly
+        base.detach = Some(true);
+        child.detach = Some(false);
+        assert_eq!(flatten_stack(&[base.clone(), child], "child").unwrap().detach, Some(false));
+
+        base.package = None;
+        assert!(matches!(flatten_stack(&[base], "base"), Err(StackError::Command(_))));
+    }
+
+    #[test]
+    fn child_removes_inherited_packages(){
+        let mut child = stack("child", Some("middle"), "run-child", &[]);
+        child.remove = Some(vec!["tools".to_string(), "libs".to_string()]);
+        let stacks = vec![
+            stack("base", None, "run-base", &["tools/1.0.0", "libs/latest"]),
+            stack("middle", Some("base"), "", &["extra"]),
+            child,
+        ];
+        let flat = flatten_stack(&stacks, "child").unwrap();
+
+        assert_eq!(flat.command, "run-child");
+        assert_eq!(flat.remove, None);
+        assert_eq!(flat.append, Some(vec![PackageName::from_str("extra")]));
+
+        let stacks = vec![
+            stack("base", None, "run-base", &["tools/1.0.0"]),
+            Stack{ remove: Some(vec!["tools".to_string()]), ..stack("child", Some("base"), "", &[]) },
+        ];
+        assert_eq!(flatten_stack(&stacks, "child").unwrap().append, None);
+    }
+
+    fn repository(packages: &[(&str, &str)]) -> Repository{
+        let mut repository = Repository::new();
+        for (name, version) in packages{
+            let version = semver::Version::parse(version).unwrap();
+            let mut package_registry = PackageRegistry::new();
+            package_registry.add_package(RepoPackage{
+                name: name.to_string(),
+                version: version.clone(),
+                package_path: PathBuf::from(version.to_string()),
+                repository: None,
+                message: None,
+                published_at: None,
+                yanked: None,
+                deprecated: None,
+                manifest: Default::default(),
+                source: String::new(),
+            });
+            repository.packages.insert(name.to_string(), package_registry);
+        }
+        repository
+    }
+
+    #[test]
+    fn frozen_stacks_keep_floating_requests(){
+        let dir = std::env::temp_dir().join(format!("vat-stack-test-{}", std::process::id()));
+        let mut stacks = Stacks::open(dir.join(STACKS_FILE)).unwrap();
+        let mut base = stack("base", None, "run", &["tools/latest", "gone/latest"]);
+        base.append.as_mut().unwrap()[1].active = false;
+        stacks.create(base).unwrap();
+        stacks.create(stack("child", Some("base"), "", &[])).unwrap();
+
+        // the switched off package does not resolve and stays as requested
+        let repository = repository(&[("base-app", "1.0.0"), ("child-app", "1.0.0"), ("tools", "2.0.0")]);
+        let frozen = stacks.freeze("child", &repository).unwrap();
+        assert_eq!(frozen.append.as_ref().unwrap()[0].to_string(), "tools/2.0.0");
+        assert_eq!(frozen.append.as_ref().unwrap()[1].to_string(), "gone/latest");
+
+        assert!(!stacks.toggle("child", "tools", Some(false)).unwrap());
+        let thawed = stacks.thaw("child").unwrap();
+        let tools = thawed.append.as_ref().unwrap().iter().find(|package| package.name == "tools").unwrap();
+        assert_eq!(tools.to_string(), "tools/latest");
+        assert!(!tools.active);
+        assert_eq!(thawed.extends.as_deref(), Some("base"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cycles_and_missing_parents_are_errors(){
+        let stacks = vec![
+            stack("a", Some("b"), "run", &[]),
+            stack("b", Some("c"), "run", &[]),
+            stack("c", Some("a"), "run", &[]),
+        ];
+        match flatten_stack(&stacks, "a"){
+            Err(StackError::StackCycle(chain)) => assert_eq!(chain, "a -> b -> c -> a"),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+
+        let stacks = vec![stack("a", Some("missing"), "run", &[])];
+        assert!(matches!(flatten_stack(&stacks, "a"), Err(StackError::StackNotFound(name)) if name == "missing"));
+
+        let stacks = vec![stack("a", Some("b"), "", &[]), stack("b", None, "", &[])];
+        assert!(matches!(flatten_stack(&stacks, "a"), Err(StackError::Command(_))));
+    }
+}
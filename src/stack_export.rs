@@ -0,0 +1,134 @@
+use std::path::Path;
+use std::fs;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::errors::{RepositoryError, ResolutionIssues, StackError, StackResult};
+use crate::repository::{PackageName, Repository};
+use crate::stack::{Stack, Stacks};
+
+
+/// A stack as shipped to another site: its definition plus the versions it
+/// resolved to when it was exported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedStack{
+    pub stack: Stack,
+    // main package first, then the appended ones
+    pub resolved: Vec<PackageName>,
+}
+
+impl ExportedStack{
+    /// The stack pinned to the exported versions, keeping the floating
+    /// definition so it can be thawed.
+    pub fn frozen(&self) -> Stack{
+        let mut frozen = self.stack.clone();
+        if let Some(package) = self.resolved.first(){
+            frozen.package = Some(package.clone());
+        }
+        if let Some(append) = frozen.append.as_mut(){
+            for package in append.iter_mut(){
+                if let Some(pinned) = self.resolved.iter().skip(1).find(|pinned| pinned.name == package.name){
+                    package.version = pinned.version.clone();
+                }
+            }
+        }
+        frozen.frozen_at = Some(Utc::now());
+        frozen.floating = Some(Box::new(self.stack.clone()));
+        frozen
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackExport{
+    pub exported_at: DateTime<Utc>,
+    pub stacks: Vec<ExportedStack>,
+}
+
+impl StackExport{
+    /// Export the named stacks, flattened so the file does not depend on the
+    /// stacks they extend.
+    pub fn create(stacks: &Stacks, names: &[String], repository: &Repository) -> StackResult<StackExport>{
+        let mut exported = Vec::new();
+        for name in names{
+            let mut stack = stacks.flatten(name)?;
+            // a frozen stack is shipped as pinned, its floating requests may
+            // depend on stacks that are not exported
+            stack.floating = None;
+            stack.frozen_at = None;
+            exported.push(ExportedStack{ resolved: pin_packages(&stack, repository)?, stack });
+        }
+        Ok(StackExport{ exported_at: Utc::now(), stacks: exported })
+    }
+
+    pub fn save(&self, path: &Path) -> StackResult<()>{
+        let toml_string = toml::to_string(self)?;
+        fs::write(path, toml_string)?;
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> StackResult<StackExport>{
+        let toml_string = fs::read_to_string(path)?;
+        let export: StackExport = toml::from_str(&toml_string)?;
+        Ok(export)
+    }
+
+    /// Exported versions missing from the local repository, per stack.
+    pub fn check(&self, repository: &Repository) -> Vec<(String, Vec<String>)>{
+        let mut failures = Vec::new();
+        for exported in &self.stacks{
+            let active: Vec<PackageName> = exported.resolved.iter().filter(|package| package.active).cloned().collect();
+            let issues = repository.check_packages(&active);
+            if !issues.is_empty(){
+                failures.push((exported.stack.name.clone(), issues.iter().map(|issue| issue.to_string()).collect()));
+            }
+        }
+        failures
+    }
+
+    /// Add the exported stacks. With `frozen` they are pinned to the exported
+    /// versions, with `overwrite` existing stacks of the same name are replaced.
+    pub fn import(&self, stacks: &mut Stacks, frozen: bool, overwrite: bool) -> StackResult<Vec<Stack>>{
+        for exported in &self.stacks{
+            if stacks.get(&exported.stack.name).is_some() && !overwrite{
+                return Err(StackError::StackAlreadyExists(exported.stack.name.clone()));
+            }
+        }
+
+        let mut imported = Vec::new();
+        for exported in &self.stacks{
+            let stack = if frozen{ exported.frozen() }else{ exported.stack.clone() };
+            let stack = if stacks.get(&stack.name).is_some(){
+                stacks.update(stack)?
+            }else{
+                stacks.create(stack)?
+            };
+            imported.push(stack);
+        }
+        Ok(imported)
+    }
+}
+
+
+/// Versions the stack's packages resolve to. Switched off packages are
+/// pinned when they resolve and kept as requested otherwise.
+fn pin_packages(stack: &Stack, repository: &Repository) -> StackResult<Vec<PackageName>>{
+    let mut package_names = vec![stack.main_package()?.clone()];
+    package_names.extend(stack.append.clone().unwrap_or_default());
+
+    let active: Vec<PackageName> = package_names.iter().filter(|package| package.active).cloned().collect();
+    let issues = repository.check_packages(&active);
+    if !issues.is_empty(){
+        return Err(StackError::RepositoryError(RepositoryError::ResolutionError(ResolutionIssues(issues))));
+    }
+
+    let pinned = package_names.into_iter()
+        .map(|package_name| {
+            let version = repository.get_package_by_package_name(&package_name)
+                .and_then(|package_registry| package_registry.resolve_version(&package_name))
+                .unwrap_or_else(|| package_name.version.clone());
+            PackageName{ name: package_name.name, version, active: package_name.active }
+        })
+        .collect();
+    Ok(pinned)
+}
@@ -0,0 +1,349 @@
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use crate::repository::Repository;
+use crate::verify::hash_file;
+
+pub const OBJECTS_DIR: &str = "objects";
+
+
+/// Content addressed blobs under `<repository>/objects`. Published version
+/// directories hardlink their files to these, so identical files across
+/// versions are stored once. Blobs are read-only, editing one version's file
+/// in place would change every version sharing it. A repository uses the
+/// store once the directory exists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectStore{
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupeStats{
+    pub files: u64,
+    // files that were already in the store
+    pub linked: u64,
+    pub saved_bytes: u64,
+}
+
+impl ObjectStore{
+    pub fn open(repository_path: &Path) -> Option<ObjectStore>{
+        let path = repository_path.join(OBJECTS_DIR);
+        if path.is_dir(){ Some(ObjectStore{ path }) }else{ None }
+    }
+
+    /// Create the store. Only unix has the link counts pruning relies on.
+    pub fn init(repository_path: &Path) -> std::io::Result<ObjectStore>{
+        if !cfg!(unix){
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "The object store needs hardlink counts, it is only supported on unix"));
+        }
+        let path = repository_path.join(OBJECTS_DIR);
+        fs::create_dir_all(&path)?;
+        Ok(ObjectStore{ path })
+    }
+
+    /// Replace every regular file under `dir` by a link to its blob, adding
+    /// the blobs that are missing.
+    pub fn dedupe_dir(&self, dir: &Path) -> std::io::Result<DedupeStats>{
+        let mut stats = DedupeStats::default();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop(){
+            for entry in fs::read_dir(&current)?{
+                let path = entry?.path();
+                let metadata = fs::symlink_metadata(&path)?;
+                if metadata.is_dir(){
+                    pending.push(path);
+                }else if metadata.is_file(){
+                    stats.files += 1;
+                    if self.store_file(&path, &metadata)?{
+                        stats.linked += 1;
+                        stats.saved_bytes += metadata.len();
+                    }
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns true when the file was replaced by an existing blob.
+    fn store_file(&self, path: &Path, metadata: &fs::Metadata) -> std::io::Result<bool>{
+        let object_path = self.object_path(&hash_file(path)?, metadata);
+        if object_path.exists(){
+            make_read_only(&object_path)?;
+            if same_file(path, &object_path)?{
+                return Ok(false);
+            }
+            // link next to the file first so it is never missing
+            let temp_path = path.with_extension("vat-link");
+            if fs::hard_link(&object_path, &temp_path).is_err(){
+                return Ok(false);
+            }
+            fs::rename(&temp_path, path)?;
+            return Ok(true);
+        }
+        if let Some(parent) = object_path.parent(){
+            fs::create_dir_all(parent)?;
+        }
+        // a store on another filesystem keeps its own copy
+        link_or_copy(path, &object_path)?;
+        make_read_only(&object_path)?;
+        Ok(false)
+    }
+
+    /// Blobs are named by content and mode since links share their
+    /// permissions. Write bits are left out, every blob is read-only.
+    fn object_path(&self, hash: &str, metadata: &fs::Metadata) -> PathBuf{
+        let name = format!("{}.{:o}", &hash[2..], file_mode(metadata) & !0o222);
+        self.path.join(&hash[..2]).join(name)
+    }
+
+    /// Remove blobs no version links to anymore. Returns the freed bytes.
+    #[cfg(unix)]
+    pub fn prune(&self) -> std::io::Result<u64>{
+        use std::os::unix::fs::MetadataExt;
+
+        let mut freed = 0;
+        for shard in fs::read_dir(&self.path)?{
+            let shard = shard?.path();
+            if !shard.is_dir(){
+                continue;
+            }
+            for object in fs::read_dir(&shard)?{
+                let object = object?.path();
+                let metadata = fs::metadata(&object)?;
+                if metadata.nlink() == 1{
+                    fs::remove_file(&object)?;
+                    freed += metadata.len();
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Link counts are not available, which is why `init` refuses to create
+    /// a store here. A store made on unix is left to be pruned there.
+    #[cfg(not(unix))]
+    pub fn prune(&self) -> std::io::Result<u64>{
+        Ok(0)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageUsage{
+    pub name: String,
+    pub versions: usize,
+    // size of the files as seen in the version directories
+    pub logical: u64,
+    // size on disk not already counted for an earlier package
+    pub physical: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsage{
+    pub packages: Vec<PackageUsage>,
+    pub logical: u64,
+    pub physical: u64,
+}
+
+impl DiskUsage{
+    /// Sizes of the published versions, counting hardlinked files once for
+    /// the physical size.
+    pub fn measure(repository: &Repository) -> std::io::Result<DiskUsage>{
+        let mut seen = HashSet::new();
+        let mut names: Vec<&String> = repository.packages.keys().collect();
+        names.sort();
+
+        let mut usage = DiskUsage{ packages: Vec::new(), logical: 0, physical: 0 };
+        for name in names{
+            let package_registry = &repository.packages[name];
+            let mut package_usage = PackageUsage{ name: name.clone(), versions: package_registry.versions.len(), logical: 0, physical: 0 };
+            for repo_package in package_registry.versions.values(){
+                if repo_package.package_path.exists(){
+                    let (logical, physical) = measure_dir(&repo_package.package_path, &mut seen)?;
+                    package_usage.logical += logical;
+                    package_usage.physical += physical;
+                }
+            }
+            usage.logical += package_usage.logical;
+            usage.physical += package_usage.physical;
+            usage.packages.push(package_usage);
+        }
+        // blobs nothing links to anymore still take space
+        if let Some(store) = ObjectStore::open(&repository.repository_path){
+            usage.physical += measure_dir(&store.path, &mut seen)?.1;
+        }
+        Ok(usage)
+    }
+}
+
+fn measure_dir(dir: &Path, seen: &mut HashSet<(u64, u64)>) -> std::io::Result<(u64, u64)>{
+    let mut logical = 0;
+    let mut physical = 0;
+    for metadata in file_metadata(dir)?{
+        logical += metadata.len();
+        if file_id(&metadata).is_none_or(|id| seen.insert(id)){
+            physical += metadata.len();
+        }
+    }
+    Ok((logical, physical))
+}
+
+/// Size of the files under `dir` as seen in the directory.
+pub fn dir_size(dir: &Path) -> std::io::Result<u64>{
+    Ok(file_metadata(dir)?.iter().map(|metadata| metadata.len()).sum())
+}
+
+/// Bytes freed on disk by removing `dirs`. A file only counts once every link
+/// to it is gone, store blobs are pruned along with their last version.
+pub fn reclaimable(dirs: &[PathBuf], store: Option<&ObjectStore>) -> std::io::Result<u64>{
+    // files published before the store existed have no blob
+    let blobs: HashSet<(u64, u64)> = match store{
+        Some(store) => file_metadata(&store.path)?.iter().filter_map(file_id).collect(),
+        None => HashSet::new(),
+    };
+    let mut freed = 0;
+    // links found under `dirs`, links in total and size by file
+    let mut links: HashMap<(u64, u64), (u64, u64, u64)> = HashMap::new();
+    for dir in dirs.iter().filter(|dir| dir.exists()){
+        for metadata in file_metadata(dir)?{
+            match file_id(&metadata){
+                Some(id) => links.entry(id).or_insert((0, link_count(&metadata), metadata.len())).0 += 1,
+                None => freed += metadata.len(),
+            }
+        }
+    }
+    for (id, (found, total, size)) in links{
+        let store_link = if blobs.contains(&id){ 1 }else{ 0 };
+        if found + store_link >= total{
+            freed += size;
+        }
+    }
+    Ok(freed)
+}
+
+fn file_metadata(dir: &Path) -> std::io::Result<Vec<fs::Metadata>>{
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop(){
+        for entry in fs::read_dir(&current)?{
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir(){
+                pending.push(path);
+            }else if metadata.is_file(){
+                files.push(metadata);
+            }
+        }
+    }
+    Ok(files)
+}
+
+
+fn link_or_copy(source: &Path, target: &Path) -> std::io::Result<()>{
+    if fs::hard_link(source, target).is_err(){
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> std::io::Result<bool>{
+    Ok(file_id(&fs::metadata(a)?) == file_id(&fs::metadata(b)?))
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> std::io::Result<bool>{
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)>{
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)>{
+    None
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &fs::Metadata) -> u64{
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &fs::Metadata) -> u64{
+    1
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32{
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32{
+    0o644
+}
+
+/// `chmod a-w`, which applies to every link of the blob.
+fn make_read_only(path: &Path) -> std::io::Result<()>{
+    let mut permissions = fs::metadata(path)?.permissions();
+    if !permissions.readonly(){
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(all(test, unix))]
+mod tests{
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    fn write(path: &Path, content: &str, mode: u32){
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn blobs_are_read_only_and_keyed_on_mode(){
+        let dir = std::env::temp_dir().join(format!("vat-store-test-{}", std::process::id()));
+        let store = ObjectStore::init(&dir.join("repo")).unwrap();
+        let first = dir.join("repo").join("pkg").join("1.0.0");
+        let second = dir.join("repo").join("pkg").join("2.0.0");
+        let old = dir.join("repo").join("old").join("1.0.0");
+        write(&first.join("tool"), "same", 0o755);
+        write(&first.join("data"), "same", 0o644);
+        write(&second.join("tool"), "same", 0o755);
+        write(&second.join("group"), "same", 0o640);
+        write(&old.join("data"), "unique", 0o644);
+
+        store.dedupe_dir(&first).unwrap();
+        let stats = store.dedupe_dir(&second).unwrap();
+        assert_eq!(stats.linked, 1);
+
+        let tool = fs::metadata(second.join("tool")).unwrap();
+        assert_eq!(tool.ino(), fs::metadata(first.join("tool")).unwrap().ino());
+        assert_eq!(tool.permissions().mode() & 0o777, 0o555);
+        assert_eq!(tool.nlink(), 3);
+        // modes other than the exec bit are kept apart as well
+        assert_ne!(fs::metadata(second.join("group")).unwrap().ino(), fs::metadata(first.join("data")).unwrap().ino());
+        assert_eq!(fs::metadata(second.join("group")).unwrap().permissions().mode() & 0o777, 0o440);
+
+        // files without a blob are freed, linked ones once their last version goes
+        assert_eq!(reclaimable(std::slice::from_ref(&old), Some(&store)).unwrap(), 6);
+        assert_eq!(reclaimable(std::slice::from_ref(&second), Some(&store)).unwrap(), 4);
+        assert_eq!(reclaimable(&[first.clone(), second.clone()], Some(&store)).unwrap(), 12);
+
+        fs::remove_dir_all(&second).unwrap();
+        assert_eq!(store.prune().unwrap(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs;
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+use crate::atomic::write_atomic;
+use crate::config::{VatConfig, MISSING_VAT_EXECUTABLE};
+use crate::console::Console;
+use crate::errors::{SuiteError, SuiteResult};
+use crate::remote::is_safe_name;
+use crate::repository::{PackageName, Repository};
+use crate::Vat;
+
+const SUITE_FILE: &str = "suite.toml";
+
+
+/// A command exposed on PATH by a suite.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SuiteTool{
+    pub package: String,
+    pub command: String,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolClash{
+    pub tool: String,
+    // package whose command is used
+    pub package: String,
+    // packages whose command of the same name is hidden
+    pub shadowed: Vec<String>,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Suite{
+    pub name: String,
+    // packages as requested, used when refreshing
+    pub requests: Vec<PackageName>,
+    // requests pinned to the versions they resolved to
+    pub frozen: Vec<PackageName>,
+    pub tools: BTreeMap<String, SuiteTool>,
+    pub clashes: Vec<ToolClash>,
+    pub modified_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub suite_path: PathBuf,
+}
+
+impl Suite{
+    /// Resolve `requests` against the repository and write the wrappers.
+    /// Earlier packages win when two of them expose the same command.
+    pub fn create(name: &str, requests: Vec<PackageName>, repository: &Repository) -> SuiteResult<Suite>{
+        if requests.is_empty(){
+            return Err(SuiteError::InvalidSuite("A suite needs at least one package".to_string()));
+        }
+        let suite_path = Suite::get_suites_dir()?.join(name);
+        let mut suite = Suite{
+            name: name.to_string(),
+            requests,
+            frozen: Vec::new(),
+            tools: BTreeMap::new(),
+            clashes: Vec::new(),
+            modified_at: Utc::now(),
+            suite_path,
+        };
+        suite.refresh(repository)?;
+        Ok(suite)
+    }
+
+    pub fn refresh(&mut self, repository: &Repository) -> SuiteResult<()>{
+        let mut frozen = Vec::new();
+        let mut tools: BTreeMap<String, SuiteTool> = BTreeMap::new();
+        let mut clashes: BTreeMap<String, ToolClash> = BTreeMap::new();
+
+        for request in &self.requests{
+            let package_registry = repository.get_package_by_package_name(request)
+                .ok_or_else(|| SuiteError::PackageNotFound(request.to_string()))?;
+            let version = package_registry.resolve_version(request)
+                .ok_or_else(|| SuiteError::PackageNotFound(request.to_string()))?;
+            let package_path = repository.fetch_package_path(request)
+                .map_err(|_| SuiteError::PackageNotFound(request.to_string()))?;
+
+            let vat = Vat::read(package_path)?;
+            let command_names = vat.cmd.map(|cmd| cmd.names()).unwrap_or_default();
+            for command_name in command_names{
+                match tools.get(&command_name){
+                    Some(tool) => {
+                        clashes.entry(command_name.clone())
+                            .or_insert_with(|| ToolClash{ tool: command_name.clone(), package: tool.package.clone(), shadowed: Vec::new() })
+                            .shadowed.push(request.name.clone());
+                    }
+                    None => {
+                        tools.insert(command_name.clone(), SuiteTool{ package: request.name.clone(), command: command_name });
+                    }
+                }
+            }
+
+            frozen.push(PackageName{ name: request.name.clone(), version, active: request.active });
+        }
+
+        self.frozen = frozen;
+        self.tools = tools;
+        self.clashes = clashes.into_values().collect();
+        self.modified_at = Utc::now();
+        self.write_wrappers()?;
+        self.save()?;
+        Ok(())
+    }
+
+    pub fn bin_path(&self) -> PathBuf{
+        self.suite_path.join("bin")
+    }
+
+    /// Regenerate the bin directory so it only holds the current tools.
+    pub fn write_wrappers(&self) -> SuiteResult<()>{
+        // names come from the packages' commands and become file names
+        if let Some((tool_name, tool)) = self.tools.iter().find(|(tool_name, _)| !is_safe_name(tool_name)){
+            return Err(SuiteError::InvalidSuite(format!("`{}` of {} is not a valid tool name", tool_name, tool.package)));
+        }
+        let bin_path = self.bin_path();
+        if bin_path.exists(){
+            fs::remove_dir_all(&bin_path)?;
+        }
+        fs::create_dir_all(&bin_path)?;
+
+        let vat_exe = VatConfig::init()
+            .map_err(|e| SuiteError::ConfigError(e.to_string()))?
+            .get_vat_executable()
+            .ok_or_else(|| SuiteError::ConfigError(MISSING_VAT_EXECUTABLE.to_string()))?;
+        for (tool_name, tool) in &self.tools{
+            let package = self.frozen.iter()
+                .find(|package| package.name == tool.package)
+                .ok_or_else(|| SuiteError::PackageNotFound(tool.package.clone()))?;
+            let append: Vec<String> = self.frozen.iter()
+                .filter(|other| other.name != tool.package)
+                .map(|other| other.to_string())
+                .collect();
+
+            let mut args = vec!["run".to_string(), tool.command.clone(), "-p".to_string(), package.to_string()];
+            if !append.is_empty(){
+                args.push("-a".to_string());
+                args.extend(append);
+            }
+            write_wrapper(&bin_path, tool_name, &vat_exe, &args)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> SuiteResult<()>{
+        fs::create_dir_all(&self.suite_path)?;
+        let toml_string = toml::to_string(self)?;
+        write_atomic(&self.suite_path.join(SUITE_FILE), toml_string.as_bytes(), false)?;
+        Ok(())
+    }
+
+    pub fn load(name: &str) -> SuiteResult<Suite>{
+        let suite_path = Suite::get_suites_dir()?.join(name);
+        let suite_file = suite_path.join(SUITE_FILE);
+        if !suite_file.exists(){
+            return Err(SuiteError::SuiteNotFound(name.to_string()));
+        }
+
+        let toml_string = fs::read_to_string(&suite_file)?;
+
+        let mut suite: Suite = toml::from_str(&toml_string)?;
+        suite.suite_path = suite_path;
+        Ok(suite)
+    }
+
+    pub fn list() -> SuiteResult<Vec<Suite>>{
+        let suites_dir = Suite::get_suites_dir()?;
+        let mut suites = Vec::new();
+        if !suites_dir.exists(){
+            return Ok(suites);
+        }
+        for entry in fs::read_dir(suites_dir)?{
+            let entry = entry?;
+            if entry.path().join(SUITE_FILE).exists(){
+                let name = entry.file_name().to_string_lossy().to_string();
+                suites.push(Suite::load(&name)?);
+            }
+        }
+        suites.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(suites)
+    }
+
+    pub fn remove(name: &str) -> SuiteResult<()>{
+        let suite = Suite::load(name)?;
+        fs::remove_dir_all(suite.suite_path)?;
+        Ok(())
+    }
+
+    pub fn print_clashes(&self){
+        for clash in &self.clashes{
+            Console::warn(&format!("Tool `{}` from `{}` shadows the one in {}", clash.tool, clash.package, clash.shadowed.join(", ")));
+        }
+    }
+
+    pub fn get_suites_dir() -> SuiteResult<PathBuf>{
+        VatConfig::get_app_dir()
+            .map(|path| path.join("suites"))
+            .ok_or_else(|| SuiteError::InvalidSuite("Failed to get app directory".to_string()))
+    }
+}
+
+
+#[cfg(unix)]
+fn write_wrapper(bin_path: &std::path::Path, tool_name: &str, vat_exe: &std::path::Path, args: &[String]) -> SuiteResult<()>{
+    use std::os::unix::fs::PermissionsExt;
+    use crate::command::quote_posix;
+
+    let command_line = std::iter::once(vat_exe.to_string_lossy().to_string())
+        .chain(args.iter().cloned())
+        .map(|arg| quote_posix(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = format!("#!/bin/sh\n# generated by `vat wrap`, refresh with `vat suite refresh`\nexec {} -- \"$@\"\n", command_line);
+
+    let wrapper_path = bin_path.join(tool_name);
+    fs::write(&wrapper_path, script)?;
+    fs::set_permissions(&wrapper_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_wrapper(bin_path: &std::path::Path, tool_name: &str, vat_exe: &std::path::Path, args: &[String]) -> SuiteResult<()>{
+    use crate::command::quote_cmd;
+
+    let command_line = std::iter::once(vat_exe.to_string_lossy().to_string())
+        .chain(args.iter().cloned())
+        .map(|arg| quote_cmd(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = format!("@echo off\r\nrem generated by `vat wrap`, refresh with `vat suite refresh`\r\n{} -- %*\r\n", command_line);
+
+    fs::write(bin_path.join(format!("{}.cmd", tool_name)), script)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn tool_names_stay_in_the_bin_directory(){
+        let suite_path = std::env::temp_dir().join(format!("vat-suite-test-{}", std::process::id()));
+        for tool_name in ["../escape", "sub/tool", "..", ""]{
+            let mut tools = BTreeMap::new();
+            tools.insert(tool_name.to_string(), SuiteTool{ package: "pkg".to_string(), command: tool_name.to_string() });
+            let suite = Suite{
+                name: "test".to_string(),
+                requests: Vec::new(),
+                frozen: Vec::new(),
+                tools,
+                clashes: Vec::new(),
+                modified_at: Utc::now(),
+                suite_path: suite_path.clone(),
+            };
+            assert!(matches!(suite.write_wrappers(), Err(SuiteError::InvalidSuite(_))), "{:?}", tool_name);
+        }
+        assert!(!suite_path.exists());
+    }
+}
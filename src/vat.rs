@@ -1,15 +1,15 @@
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
 use fs2::FileExt;
 use std::fs::OpenOptions;
 use semver::Version;
-use std::collections::HashMap;
-use std::time::SystemTime;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use chrono::Utc;
+use std::time::{Duration, Instant};
 use dirs;
 
-use crate::command::Commands;
+use crate::command::{Command, Commands, Limits};
 use crate::package::Package;
 use crate::environment::{Environments, EnvVar};
 use crate::dependencies::Dependency;
@@ -17,8 +17,12 @@ use crate::errors::{PackageResult, PackageError};
 use crate::git::Git;
 use crate::console::Console;
 use crate::variables::Variables;
+use crate::process::{kill_pid, process_start_time, ProcessRecord, ProcessRegistry};
+use crate::plan::{Launcher, ResolvedPackage, RunPlan};
+use crate::atomic::write_atomic;
 
 const VAT_FILE: &str = "vat.toml";  
+const DEFAULT_TIMEOUT_GRACE: u64 = 10;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Vat{
@@ -31,6 +35,12 @@ pub struct Vat{
     pub dependencies: Option<Vec<Dependency>>,
     #[serde(skip)]  
     pub resolved_env: HashMap<String, String>,
+    // packages appended to the environment, recorded for detached processes
+    #[serde(skip)]
+    pub context: Vec<String>,
+    // limits requested at run time, on top of the command's own
+    #[serde(skip)]
+    pub limits: Option<Limits>,
 }
 
 
@@ -44,6 +54,8 @@ impl Vat{
             cmd: None,
             dependencies: None,
             resolved_env: HashMap::new(),
+            context: Vec::new(),
+            limits: None,
         };
         vat
     }
@@ -153,19 +165,8 @@ impl Vat{
 
     pub fn save(&self) -> PackageResult<Self> {
         let vat_toml_path = self.package_path.join(VAT_FILE);
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&vat_toml_path)?;
-        
-        FileExt::lock_exclusive(&file)?;
-        
         let toml_string = toml::to_string(self)?;
-        file.write_all(toml_string.as_bytes())?;
-        
-        FileExt::unlock(&file)?;
-        
+        write_atomic(&vat_toml_path, toml_string.as_bytes(), false)?;
         Ok(self.clone())
     }
 
@@ -196,6 +197,14 @@ impl Vat{
         } 
     }
 
+    pub fn set_context(&mut self, context: Vec<String>){
+        self.context = context;
+    }
+
+    pub fn set_limits(&mut self, limits: Option<Limits>){
+        self.limits = limits;
+    }
+
     pub fn set_resolved_env(&mut self, resolved_env: HashMap<String, String>){
         self.resolved_env = resolved_env;
     }
@@ -256,153 +265,118 @@ impl Vat{
     }
 
 
-    pub fn run(&self, command_name: &str, detach: bool, add_env: Option<HashMap<String, String>>, additional_cmds: Option<Vec<String>>) -> PackageResult<()>{
-        if let Some(cmd) = &self.cmd{
-            let command = cmd.get_command(command_name);
-            if let Some(command) = command{
-                let length = command.values.len();
-                if length > 0 {
-                    let first_commnad = command.values.first();
-                    if let Some(first_commnad) = first_commnad{
-                        let mut command_process = std::process::Command::new(first_commnad);
-                        for arg in &command.values[1..] {
-                            command_process.arg(arg);
-                        }
-                        let mut resolved_env = self.resolved_env.clone();
-                        if let Some(add_env) = add_env{
-                            resolved_env.extend(add_env);
-                        }
-                        #[cfg(target_os = "macos")]
-                        {
-                            if let Some(path) = resolved_env.get_mut("PATH") {
-                                *path = expand_tilde_in_path(path);
-                            }
-                        }
-                        command_process.envs(&resolved_env);
-                        Console::dim(&format!("Package Name: {}", self.package.name));
-                        Console::dim(&format!("Package Path: {}", self.package_path.to_string_lossy().to_string()));
-                        Console::info(&format!("Running command: {:?} from package: {}", command_name, self.package.name));
-                        if detach{
-                            // command_process
-                            // .stdout(Stdio::null())
-                            // .stderr(Stdio::null())
-                            // .stdin(Stdio::null());
-                            // command_process.spawn()?;
-
-                            #[cfg(target_os = "macos")]
-                            {
-                                // let full_command = command.values.join(" ");
-                                // std::process::Command::new("open")
-                                //     .arg("-a")
-                                //     .arg("Terminal")
-                                //     .arg(format!("--args bash -c '{}; exec bash'", full_command))
-                                //     .spawn()?;
-
-                                if command.cwd.is_some(){
-                                    command_process.current_dir(command.cwd.unwrap());
-                                }
-
-                                command_process
-                                .stdout(std::process::Stdio::null())
-                                .stderr(std::process::Stdio::null())
-                                .stdin(std::process::Stdio::null());
-                                command_process.spawn()?;
-                            }
-
-                            #[cfg(target_os = "linux")]
-                            {
-                                let full_command = command.values.join(" ");
-                                std::process::Command::new("gnome-terminal")
-                                    .arg("--")
-                                    .arg("bash")
-                                    .arg("-c")
-                                    .arg(format!("{}; exec bash", full_command))
-                                    .spawn()?;
-                            }
-
-
-                            #[cfg(target_os = "windows")]
-                            {
-                                use std::env::temp_dir;
-
-                                // Build command as a single string
-                                let mut full_command = command.values.join(" ");
-                                
-                                // Add additional commands if provided
-                                if let Some(additional_cmds) = additional_cmds {
-                                    for additional_cmd in additional_cmds {
-                                        // Ensure the additional command is not processed through path_resolve
-                                        let clean_cmd = additional_cmd.trim();
-                                        // Escape % characters to prevent Windows environment variable expansion
-                                        let escaped_cmd = clean_cmd.replace("%", "%%");
-                                        // Quote the additional command if it contains spaces
-                                        let quoted_cmd = if escaped_cmd.contains(' ') {
-                                            format!("\"{}\"", escaped_cmd)
-                                        } else {
-                                            escaped_cmd.to_string()
-                                        };
-                                        full_command = format!("{} {}", full_command, quoted_cmd);
-                                    }
-                                }
-
-                                // Build the .bat file content with env setup
-                                let mut bat_content = String::new();
-                                for (k, v) in &resolved_env {
-                                    // Properly escape and quote environment variable values
-                                    let escaped_value = v.replace("\"", "\"\""); 
-                                    bat_content.push_str(&format!("set \"{}={}\"\n", k, escaped_value));
-                                }
-                                // Debug: print environment variables
-                                println!("DEBUG: Environment variables:");
-                                for (k, v) in &resolved_env {
-                                    println!("  {} = {}", k, v);
-                                }
-                                // Debug: print the full command before writing to batch file
-                                println!("DEBUG: Full command: {}", full_command);
-                                bat_content.push_str(&format!("{}\n", full_command));
-                                // bat_content.push_str("pause\n"); // Optional: so terminal stays open
-                                bat_content.push_str("exit\n"); // Close the terminal when finished
-
-                                // Write batch file to temp dir
-                                let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
-                                let bat_path = temp_dir().join(format!("run_cmd_{}.bat", timestamp));
-                                let mut file = File::create(&bat_path)?;
-                                file.write_all(bat_content.as_bytes())?;
-
-                                // Launch cmd with the batch file
-                                let mut cmd_process = std::process::Command::new("cmd");
-                                cmd_process.args(&["/C", &format!("start {}", bat_path.display())]);
-                                
-                                // Set working directory if specified
-                                if let Some(cwd) = &command.cwd {
-                                    cmd_process.current_dir(cwd);
-                                }
-                                
-                                cmd_process.spawn()?;
-                            }
-                          
-                        }else{
-                            // Add additional commands to the process for non-detached execution
-                            if let Some(additional_cmds) = additional_cmds {
-                                for additional_cmd in additional_cmds {
-                                    command_process.arg(additional_cmd);
-                                }
-                            }
-                            command_process.output()?;
-                        }
-                        Ok(())
-                    }else{
-                        Err(PackageError::CommandNotFound(command_name.to_string()))
-                    }
-                }else{
-                    Err(PackageError::CommandNotFound(command_name.to_string()))
+    pub fn get_command(&self, command_name: &str) -> PackageResult<Command>{
+        let command = self.cmd.as_ref().and_then(|cmd| cmd.get_command(command_name));
+        match command{
+            Some(command) if !command.values.is_empty() => Ok(command),
+            _ => Err(PackageError::CommandNotFound(command_name.to_string())),
+        }
+    }
+
+
+    /// Describe what `run` would execute, without spawning anything.
+    pub fn plan(&self, command_name: &str, launcher: Launcher, add_env: Option<HashMap<String, String>>, additional_cmds: Option<Vec<String>>) -> PackageResult<RunPlan>{
+        let command = self.get_command(command_name)?;
+        let additional_cmds = additional_cmds.unwrap_or_default();
+
+        let mut env: BTreeMap<String, String> = std::env::vars().collect();
+        env.extend(self.run_env(add_env));
+
+        Ok(RunPlan{
+            packages: vec![ResolvedPackage::from_vat(self)],
+            command: command_name.to_string(),
+            argv: command.argv(&additional_cmds),
+            cwd: command.cwd.as_ref().map(PathBuf::from),
+            launcher,
+            limits: self.run_limits(&command),
+            env,
+        })
+    }
+
+    /// Variables set on the process on top of the inherited environment.
+    pub fn run_env(&self, add_env: Option<HashMap<String, String>>) -> HashMap<String, String>{
+        let mut resolved_env = self.resolved_env.clone();
+        if let Some(add_env) = add_env{
+            resolved_env.extend(add_env);
+        }
+        #[cfg(target_os = "macos")]
+        {
+            if let Some(path) = resolved_env.get_mut("PATH") {
+                *path = expand_tilde_in_path(path);
+            }
+        }
+        resolved_env
+    }
+
+    pub fn run_limits(&self, command: &Command) -> Limits{
+        command.limits.clone().unwrap_or_default()
+            .merge(&self.limits.clone().unwrap_or_default())
+    }
+
+
+    pub fn run(&self, command_name: &str, launcher: Launcher, add_env: Option<HashMap<String, String>>, additional_cmds: Option<Vec<String>>) -> PackageResult<()>{
+        let command = self.get_command(command_name)?;
+        let additional_cmds = additional_cmds.unwrap_or_default();
+        let resolved_env = self.run_env(add_env);
+        let limits = self.run_limits(&command);
+
+        Console::dim(&format!("Package Name: {}", self.package.name));
+        Console::dim(&format!("Package Path: {}", self.package_path.to_string_lossy()));
+        Console::info(&format!("Running command: {:?} from package: {}", command_name, self.package.name));
+
+        match launcher{
+            Launcher::Detached => {
+                if limits.timeout.is_some(){
+                    Console::warn("Timeouts are not enforced for detached runs, use `vat kill` instead");
                 }
-            }else{
-                Err(PackageError::CommandNotFound(command_name.to_string()))
+                let _registry_lock = ProcessRegistry::lock()?;
+                let mut registry = ProcessRegistry::load()?;
+                let (id, log_path, log_file) = registry.create_log()?;
+
+                let mut command_process = detached_process(&command, &additional_cmds, log_file)?;
+                command_process.envs(&resolved_env);
+                limits.apply(&mut command_process);
+                let child = command_process.spawn()?;
+
+                registry.register(ProcessRecord{
+                    id,
+                    pid: child.id(),
+                    package: self.package.name.clone(),
+                    version: self.package.version.to_string(),
+                    command: command_name.to_string(),
+                    argv: command.argv(&additional_cmds),
+                    cwd: command.cwd.as_ref().map(PathBuf::from),
+                    started_at: Utc::now(),
+                    start_time: process_start_time(child.id()),
+                    context: self.context.clone(),
+                    log_path,
+                })?;
+                Console::dim(&format!("Started process {} (pid {}), see `vat logs {}`", id, child.id(), id));
+            }
+            Launcher::Terminal => {
+                // the terminal owns the process from here, nothing is waited on
+                if !limits.is_empty(){
+                    Console::warn("Limits are not enforced for terminal runs");
+                }
+                terminal_process(&command, &additional_cmds, &resolved_env)?.spawn()?;
+            }
+            Launcher::Inline => {
+                let mut command_process = command.to_process(&additional_cmds);
+                command_process.envs(&resolved_env);
+                limits.apply(&mut command_process);
+                // a timeout terminates the whole group, `sh -c` children included
+                let foreground = limits.timeout.map(|_| {
+                    own_process_group(&mut command_process);
+                    ForegroundGroup::current()
+                });
+                let child = command_process.spawn()?;
+                if let Some(foreground) = &foreground{
+                    foreground.give(child.id());
+                }
+                wait_with_limits(child, command_name, &limits)?;
             }
-        }else{
-            Err(PackageError::CommandNotFound(command_name.to_string()))
         }
+        Ok(())
     }
 
 
@@ -453,6 +427,255 @@ impl Vat{
 
 }
 
+/// Build the process used to launch `command` in the background. Output goes
+/// to `log_file` and the process no longer depends on the launching terminal.
+pub fn detached_process(command: &Command, additional_args: &[String], log_file: File) -> PackageResult<std::process::Command>{
+    let mut process = command.to_process(additional_args);
+    process
+        .stdin(std::process::Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        process.process_group(0);
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+        process.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+
+    Ok(process)
+}
+
+/// Terminal emulators tried in order when `$TERMINAL` is not set.
+#[cfg(all(unix, not(target_os = "macos")))]
+const TERMINALS: [&str; 4] = ["x-terminal-emulator", "gnome-terminal", "konsole", "xterm"];
+
+/// Build the process that opens `command` in a new terminal window. The argv
+/// reaches the terminal as separate words and a shell keeps the window open
+/// once the command exits.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn terminal_process(command: &Command, additional_args: &[String], env: &HashMap<String, String>) -> PackageResult<std::process::Command>{
+    let terminal = find_terminal()
+        .ok_or_else(|| PackageError::CommandFailed("No terminal emulator found, set $TERMINAL".to_string()))?;
+    let mut process = std::process::Command::new(&terminal);
+    // gnome-terminal wants `--`, the others follow xterm's `-e`
+    if terminal.file_name().is_some_and(|name| name == "gnome-terminal"){
+        process.arg("--");
+    }else{
+        process.arg("-e");
+    }
+    process
+        .args(["sh", "-c", "\"$@\"; exec \"${SHELL:-sh}\"", "sh"])
+        .args(command.argv(additional_args))
+        .envs(env);
+    if let Some(cwd) = &command.cwd{
+        process.current_dir(cwd);
+    }
+    Ok(process)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn find_terminal() -> Option<PathBuf>{
+    let path = std::env::var_os("PATH")?;
+    let dirs: Vec<PathBuf> = std::env::split_paths(&path).collect();
+    std::env::var("TERMINAL").ok()
+        .into_iter()
+        .chain(TERMINALS.iter().map(|terminal| terminal.to_string()))
+        .find_map(|terminal| {
+            let terminal = PathBuf::from(terminal);
+            if terminal.is_absolute(){
+                return Some(terminal).filter(|terminal| terminal.is_file());
+            }
+            dirs.iter().map(|dir| dir.join(&terminal)).find(|candidate| candidate.is_file())
+        })
+}
+
+/// Terminal.app does not pass on arguments or the environment, so they go
+/// into a `.command` script it opens, every word quoted on its own.
+#[cfg(target_os = "macos")]
+pub fn terminal_process(command: &Command, additional_args: &[String], env: &HashMap<String, String>) -> PackageResult<std::process::Command>{
+    use std::os::unix::fs::PermissionsExt;
+    use crate::command::quote_posix;
+
+    let mut script = String::from("#!/bin/sh\n");
+    for (key, value) in env{
+        if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'){
+            script.push_str(&format!("export {}={}\n", key, quote_posix(value)));
+        }
+    }
+    if let Some(cwd) = &command.cwd{
+        script.push_str(&format!("cd {} || exit 1\n", quote_posix(cwd)));
+    }
+    let words: Vec<String> = command.argv(additional_args).iter().map(|arg| quote_posix(arg)).collect();
+    script.push_str(&format!("{}\n", words.join(" ")));
+
+    let script_path = std::env::temp_dir().join(format!("vat-run-{}-{}.command", std::process::id(), Utc::now().timestamp_millis()));
+    std::fs::write(&script_path, script)?;
+    std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o700))?;
+
+    let mut process = std::process::Command::new("open");
+    process.arg("-a").arg("Terminal").arg(&script_path);
+    Ok(process)
+}
+
+/// A new console window for the command itself, nothing is re-joined.
+#[cfg(target_os = "windows")]
+pub fn terminal_process(command: &Command, additional_args: &[String], env: &HashMap<String, String>) -> PackageResult<std::process::Command>{
+    use std::os::windows::process::CommandExt;
+    const CREATE_NEW_CONSOLE: u32 = 0x00000010;
+
+    let mut process = command.to_process(additional_args);
+    process.envs(env).creation_flags(CREATE_NEW_CONSOLE);
+    Ok(process)
+}
+
+/// Wait for an inline run, enforcing the wall-clock timeout. Reports which
+/// limit ended the process when it did not exit on its own. Any other exit
+/// status is the command's own result and only reported.
+pub fn wait_with_limits(mut child: std::process::Child, command_name: &str, limits: &Limits) -> PackageResult<()>{
+    let started = Instant::now();
+    let mut terminated_at: Option<Instant> = None;
+    let grace = Duration::from_secs(limits.timeout_grace.unwrap_or(DEFAULT_TIMEOUT_GRACE));
+
+    let status = loop{
+        if let Some(status) = child.try_wait()?{
+            break status;
+        }
+
+        if let Some(timeout) = limits.timeout{
+            match terminated_at{
+                None if started.elapsed() >= Duration::from_secs(timeout) => {
+                    Console::warn(&format!("Command {:?} reached its {}s timeout, terminating", command_name, timeout));
+                    terminate(&child);
+                    terminated_at = Some(Instant::now());
+                }
+                Some(terminated) if terminated.elapsed() >= grace => {
+                    let _ = kill_pid(child.id(), true);
+                    child.kill()?;
+                }
+                _ => {}
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    if let (Some(timeout), Some(_)) = (limits.timeout, terminated_at){
+        return Err(PackageError::LimitExceeded(format!("{:?} killed after the {}s timeout", command_name, timeout)));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal(){
+            if signal == libc::SIGXCPU{
+                return Err(PackageError::LimitExceeded(format!("{:?} killed after using {}s of cpu time", command_name, limits.cpu_time.unwrap_or_default())));
+            }
+            if let Some(max_memory) = limits.max_memory{
+                return Err(PackageError::LimitExceeded(format!("{:?} killed by signal {}, it may have hit the {}MB memory limit", command_name, signal, max_memory)));
+            }
+            Console::warn(&format!("{:?} killed by signal {}", command_name, signal));
+            return Ok(());
+        }
+    }
+
+    if !status.success(){
+        Console::warn(&format!("{:?} exited with {}", command_name, status));
+    }
+    Ok(())
+}
+
+/// SIGTERM the child's process group, see `own_process_group`.
+fn terminate(child: &std::process::Child){
+    let _ = kill_pid(child.id(), false);
+}
+
+/// Make an inline run lead its own process group. When vat owns the
+/// terminal the group takes it over, so it still reads input and gets Ctrl-C.
+#[cfg(unix)]
+fn own_process_group(process: &mut std::process::Command){
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        process.pre_exec(|| {
+            let foreground = libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp();
+            if libc::setpgid(0, 0) != 0{
+                return Err(std::io::Error::last_os_error());
+            }
+            if foreground{
+                set_foreground(libc::getpid());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_process: &mut std::process::Command){
+}
+
+/// Whether vat owns the terminal, checked before spawning since the child
+/// takes it over as it starts. The terminal goes back to vat when dropped.
+#[cfg(unix)]
+struct ForegroundGroup{
+    restore: Option<libc::pid_t>,
+}
+
+#[cfg(unix)]
+impl ForegroundGroup{
+    fn current() -> Self{
+        let own = unsafe { libc::getpgrp() };
+        let foreground = unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetpgrp(libc::STDIN_FILENO) == own };
+        Self{ restore: Some(own).filter(|_| foreground) }
+    }
+
+    /// The child does the same, whichever runs first.
+    fn give(&self, pgid: u32){
+        if self.restore.is_some(){
+            set_foreground(pgid as libc::pid_t);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ForegroundGroup{
+    fn drop(&mut self){
+        if let Some(own) = self.restore{
+            set_foreground(own);
+        }
+    }
+}
+
+/// `tcsetpgrp` from a background group stops the caller with SIGTTOU unless
+/// it is ignored.
+#[cfg(unix)]
+fn set_foreground(pgid: libc::pid_t){
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+#[cfg(not(unix))]
+struct ForegroundGroup;
+
+#[cfg(not(unix))]
+impl ForegroundGroup{
+    fn current() -> Self{
+        ForegroundGroup
+    }
+
+    fn give(&self, _pgid: u32){
+    }
+}
+
 pub fn expand_tilde_in_path(path: &str) -> String {
     let home = dirs::home_dir().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
     path.split(':')
@@ -466,3 +689,23 @@ pub fn expand_tilde_in_path(path: &str) -> String {
         .collect::<Vec<_>>()
         .join(":")
 }
+
+
+#[cfg(all(test, unix))]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn exit_status_is_not_an_error(){
+        let child = std::process::Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        assert!(wait_with_limits(child, "fails", &Limits::default()).is_ok());
+
+        let child = std::process::Command::new("sh").args(["-c", "kill -TERM $$"]).spawn().unwrap();
+        assert!(wait_with_limits(child, "signalled", &Limits::default()).is_ok());
+
+        // running out of time is still reported as a limit
+        let limits = Limits{ timeout: Some(0), timeout_grace: Some(0), ..Default::default() };
+        let child = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        assert!(matches!(wait_with_limits(child, "slow", &limits), Err(PackageError::LimitExceeded(_))));
+    }
+}
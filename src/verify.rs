@@ -0,0 +1,109 @@
+use std::path::Path;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use sha2::{Digest, Sha256};
+use semver::Version;
+
+use crate::repository::RepoPackage;
+
+
+/// Relative path (with `/` separators) to SHA-256 of every file under `root`.
+/// Symlinks are recorded by their target instead of followed.
+pub fn build_manifest(root: &Path) -> std::io::Result<BTreeMap<String, String>>{
+    let mut manifest = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop(){
+        for entry in fs::read_dir(&dir)?{
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir(){
+                pending.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(root).unwrap_or(&path)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            manifest.insert(relative, hash_entry(&path, &metadata)?);
+        }
+    }
+    Ok(manifest)
+}
+
+fn hash_entry(path: &Path, metadata: &fs::Metadata) -> std::io::Result<String>{
+    let mut hasher = Sha256::new();
+    if metadata.is_symlink(){
+        hasher.update(b"symlink:");
+        hasher.update(fs::read_link(path)?.to_string_lossy().as_bytes());
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
+    hash_file(path)
+}
+
+pub fn hash_file(path: &Path) -> std::io::Result<String>{
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+
+/// Differences between a published version and the manifest recorded when
+/// it was published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport{
+    pub name: String,
+    pub version: Version,
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    // published before manifests were recorded, nothing to compare
+    pub unrecorded: bool,
+}
+
+impl VerifyReport{
+    pub fn check(repo_package: &RepoPackage) -> std::io::Result<VerifyReport>{
+        let mut report = VerifyReport{
+            name: repo_package.name.clone(),
+            version: repo_package.version.clone(),
+            modified: Vec::new(),
+            missing: Vec::new(),
+            extra: Vec::new(),
+            unrecorded: repo_package.manifest.is_empty(),
+        };
+        if report.unrecorded{
+            return Ok(report);
+        }
+
+        let current = if repo_package.package_path.exists(){
+            build_manifest(&repo_package.package_path)?
+        }else{
+            BTreeMap::new()
+        };
+        for (file, checksum) in &repo_package.manifest{
+            match current.get(file){
+                Some(current_checksum) if current_checksum != checksum => report.modified.push(file.clone()),
+                Some(_) => {}
+                None => report.missing.push(file.clone()),
+            }
+        }
+        report.extra = current.keys()
+            .filter(|file| !repo_package.manifest.contains_key(*file))
+            .cloned()
+            .collect();
+        Ok(report)
+    }
+
+    pub fn is_ok(&self) -> bool{
+        self.modified.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+
+    /// One line per problem, `M`odified, `D`eleted or `A`dded.
+    pub fn problems(&self) -> Vec<String>{
+        let mut problems = Vec::new();
+        problems.extend(self.modified.iter().map(|file| format!("M {}", file)));
+        problems.extend(self.missing.iter().map(|file| format!("D {}", file)));
+        problems.extend(self.extra.iter().map(|file| format!("A {}", file)));
+        problems
+    }
+}